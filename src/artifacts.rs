@@ -0,0 +1,34 @@
+//! Files staticsync leaves next to the ones it syncs, which must never be synced themselves.
+
+use std::path::Path;
+
+/// Partially copied file, renamed over the destination once complete.
+pub const STAGING_SUFFIX: &str = ".staticsync-tmp";
+/// How far an interrupted copy got, next to its staging file.
+pub const CHECKPOINT_SUFFIX: &str = ".staticsync-resume";
+/// What a file was like before the last pass replaced it, for `staticsync undo`.
+pub const UNDO_SUFFIX: &str = ".staticsync-undo";
+
+/// Where deleted files wait out their grace period, at the top of each directory of an entry.
+pub const TRASH_DIR: &str = ".staticsync-trash";
+
+const SUFFIXES: &[&str] = &[STAGING_SUFFIX, CHECKPOINT_SUFFIX, UNDO_SUFFIX];
+
+/// Glob patterns matching every artifact, for tools that need to skip them too.
+pub fn patterns() -> Vec<String> {
+    let mut patterns: Vec<String> = SUFFIXES.iter().map(|x| format!("*{}", x)).collect();
+    patterns.push(format!("{}/", TRASH_DIR));
+    patterns
+}
+
+pub fn is_artifact(path: &Path) -> bool {
+    if path.components().any(|x| x.as_os_str() == TRASH_DIR) { return true }
+
+    match path.file_name() {
+        Some(name) => {
+            let name = name.to_string_lossy();
+            SUFFIXES.iter().any(|x| name.ends_with(x))
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,50 @@
+//! Keeps what staticsync leaves around, like the trash, the common ancestors and the history, out
+//! of Time Machine backups and Spotlight on macOS, so they aren't backed up and indexed twice.
+//! Undo backups are left alone, since they can be hard links to the very files being synced.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// File inside a directory that keeps Spotlight out of it.
+pub const SPOTLIGHT_MARKER: &str = ".metadata_never_index";
+
+/// Turns exclusions on for the rest of the run.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Leaves `path` out of backups, and out of Spotlight if it's a directory. Doesn't do anything
+/// unless exclusions are on, or on other systems. It's only ever a nicety, so failing is fine.
+pub fn exclude(path: &Path) {
+    if ENABLED.load(Ordering::Relaxed) { mark(path); }
+}
+
+#[cfg(target_os = "macos")]
+fn mark(path: &Path) {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::unix::ffi::OsStrExt;
+
+    // What `tmutil addexclusion` sets: a binary plist of the string "com.apple.backupd".
+    const NAME: &[u8] = b"com.apple.metadata:com_apple_backup_excludeItem\0";
+    const VALUE: &[u8] = b"bplist00_\x10\x11com.apple.backupd\x08\
+        \x00\x00\x00\x00\x00\x00\x01\x01\
+        \x00\x00\x00\x00\x00\x00\x00\x01\
+        \x00\x00\x00\x00\x00\x00\x00\x00\
+        \x00\x00\x00\x00\x00\x00\x00\x1c";
+
+    if let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) {
+        unsafe {
+            ::libc::setxattr(c_path.as_ptr(), NAME.as_ptr() as *const ::libc::c_char,
+                VALUE.as_ptr() as *const ::libc::c_void, VALUE.len(), 0, 0);
+        }
+    }
+    if path.is_dir() {
+        let _ = File::create(path.join(SPOTLIGHT_MARKER));
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn mark(_path: &Path) {}
@@ -0,0 +1,999 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use artifacts;
+use crypto::{digest::Digest, sha1::Sha1};
+use dirs;
+use exclude::{Exclude, GitIgnore};
+use glob;
+use hashing::Algorithm;
+use loops;
+use policy::Policy;
+use redact;
+use requirements::Requirement;
+use schedule::{self, PauseWindow, WeekTime};
+use serde_json::{self, Value as JSONValue};
+use walk;
+use warnings::Warning;
+use SetupError;
+
+/// Paths of the files kept in sync with each other, one list per pair.
+pub type Pairs = Vec<Vec<String>>;
+
+/// What the paths of an entry point at.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Files,
+    /// Directories synced file by file.
+    Directories,
+    /// A glob pattern, whose matches are synced into the directory given second.
+    Glob,
+}
+
+/// Pairs re-hashed per verification round unless the config says otherwise.
+const VERIFY_SAMPLE: usize = 10;
+
+/// What happens to a file in a directory entry once its counterpart is deleted.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Delete {
+    /// It stays.
+    Off,
+    /// It stays, but staticsync says it would have deleted it.
+    DryRun,
+    On,
+}
+
+/// What happens when the files of a pair both changed since they were last in sync.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnConflict {
+    /// The pair is held until someone sorts it out.
+    Skip,
+    Newest,
+    /// The first path wins.
+    Left,
+    /// The second path wins.
+    Right,
+    /// The newest wins, but the other one is kept next to it under another name.
+    KeepBoth,
+}
+
+/// How the files of a pair are told apart.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compare {
+    /// By mtime, only hashing files whose mtimes differ.
+    Mtime,
+    /// By contents alone, hashing every file on every check.
+    Hash,
+    /// By mtime without ever hashing, files whose mtimes differ are just copied.
+    Quick,
+    /// By mtime, reading files whose mtimes differ side by side until they differ instead of
+    /// hashing them.
+    Bytes,
+}
+
+/// Which way the files of an entry are copied.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Both,
+    /// From the first path to the others only.
+    LeftToRight,
+    /// From the last path to the others only.
+    RightToLeft,
+}
+
+/// What a file has to pass before it's replaced, so damage like a file emptied or overwritten with
+/// junk doesn't spread.
+pub struct ReplaceCheck {
+    /// Largest share of the file that may change at once, between 0 and 1.
+    pub max_change: Option<f64>,
+    /// Whether the new version may be the old one cut short.
+    pub allow_truncation: bool,
+}
+
+/// How much of what staticsync keeps around is kept, so it can't fill the disk. Rules that aren't
+/// set keep everything.
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    /// Updates of each pair the history keeps.
+    pub keep_versions: Option<usize>,
+    /// How long updates stay in the history, and deleted files in the trash.
+    pub max_age: Option<Duration>,
+    /// Bytes the trash of an entry and its updates in the history may take up, each.
+    pub max_size: Option<u64>,
+}
+
+impl Retention {
+    pub fn is_set(&self) -> bool {
+        self.keep_versions.is_some() || self.max_age.is_some() || self.max_size.is_some()
+    }
+}
+
+/// A set of files kept in sync with each other, or of directories whose files are.
+pub struct Entry {
+    /// What the entry is called in logs and on the command line, instead of its paths.
+    pub name: Option<String>,
+    pub paths: Vec<String>,
+    pub kind: Kind,
+    /// Hold off replacing a file with uncommitted changes in its git work tree.
+    pub git_guard: bool,
+    /// Read files other programs keep locked through a shadow copy of their volume, on Windows.
+    pub vss: bool,
+    /// Where copies are staged instead of next to the destination.
+    pub temp_dir: Option<PathBuf>,
+    /// Script deciding whether a copy goes ahead.
+    pub policy: Option<Policy>,
+    /// Entries sharing a group are either all updated in a pass or not at all.
+    pub group: Option<String>,
+    pub tags: Vec<String>,
+    /// Entries with a higher one are synced first in every pass.
+    pub priority: i64,
+    /// Pairs updated more often than this in an hour are warned about.
+    pub max_changes_per_hour: Option<u64>,
+    /// Touching this file gets the entry synced right away.
+    pub trigger_file: Option<PathBuf>,
+    /// Files left out of directory and glob entries, from both the entry and the whole config.
+    pub exclude: Exclude,
+    /// Leave out what git ignores too, for directories in a work tree.
+    pub gitignore: bool,
+    pub delete: Delete,
+    /// How long deleted files stay in the trash before they're gone for good.
+    pub delete_grace: Option<Duration>,
+    /// Its own retention rules, along with the config-wide ones it doesn't override.
+    pub retention: Retention,
+    pub on_conflict: OnConflict,
+    /// Whether text files that both changed get their changes merged before `on_conflict` is
+    /// asked.
+    pub merge: bool,
+    /// Shell command asked to merge the two files of a pair when both changed, before
+    /// `on_conflict` is.
+    pub conflict_command: Option<String>,
+    pub direction: Direction,
+    /// The path the others are always made like, even when they're newer.
+    pub master: Option<usize>,
+    /// Whether the master is a golden copy: changes to the others are drift, and files only they
+    /// have don't belong there.
+    pub golden: bool,
+    pub compare: Compare,
+    /// How far apart mtimes can be and still count as the same.
+    pub mtime_tolerance: Duration,
+    /// Replacements failing this are held like conflicts.
+    pub verify_replace: Option<ReplaceCheck>,
+    /// Shell command taking a filesystem snapshot of a side before a pass first changes it.
+    pub snapshot_command: Option<String>,
+    /// What has to be there for the entry to be synced at all.
+    pub requires: Vec<Requirement>,
+    /// Warnings not worth giving about this entry.
+    pub suppress_warnings: Vec<Warning>,
+    /// None of the paths were there when the config was loaded, so what they are and whether they
+    /// make sense is only found out once they show up.
+    pub pending: bool,
+}
+
+/// Borrows a list of paths the way most of the code takes them.
+pub fn as_strs(paths: &[String]) -> Vec<&str> {
+    paths.iter().map(|x| x.as_str()).collect()
+}
+
+impl Entry {
+    /// The sets of files this entry keeps in sync: its own paths, or for directories every file
+    /// found under all of them, matched up by relative path.
+    pub fn pairs(&self) -> io::Result<Vec<Vec<String>>> {
+        self.scan().map(|(pairs, _)| pairs)
+    }
+
+    /// Like `pairs`, also returning the pairs that only have a file on some of the sides.
+    pub fn scan(&self) -> io::Result<(Pairs, Pairs)> {
+        match self.kind {
+            Kind::Files => Ok((vec![self.paths.clone()], Vec::new())),
+            Kind::Directories => self.directory_pairs(),
+            Kind::Glob => self.glob_pairs(),
+        }
+    }
+
+    /// Whether what the files were like when they were last in sync is kept, to merge from.
+    pub fn keeps_bases(&self) -> bool {
+        self.merge || self.conflict_command.is_some()
+    }
+
+    /// How the entry is shown in logs: its name, or its paths if it has none.
+    pub fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => redact::join(&self.paths, " <-> "),
+        }
+    }
+
+    /// Whether the entry can't be synced right now because its paths aren't there. A directory
+    /// that's missing is much more likely to be on a drive that isn't mounted than meant to be
+    /// created, so directory entries need all of theirs. Nor are paths that are there while the
+    /// mount point they're meant to be on isn't mounted.
+    pub fn unavailable(&self) -> bool {
+        if self.requires.iter().any(|x| matches!(x, Requirement::Mount(_)) && x.unmet().is_some()) { return true }
+
+        match self.kind {
+            Kind::Directories => self.paths.iter().any(|x| !Path::new(x).is_dir()),
+            _ => unavailable(&self.paths),
+        }
+    }
+
+    /// What's wrong if one of the paths turned into something else since the config was loaded,
+    /// like a file that was replaced by a directory. Syncing it as it was would fail halfway, or
+    /// worse, replace whatever is there now.
+    pub fn type_change(&self) -> Option<String> {
+        if self.pending { return None }
+
+        for (i, path) in self.paths.iter().map(Path::new).enumerate() {
+            let link = match path.symlink_metadata() {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            let problem = match self.kind {
+                Kind::Directories if !path.is_dir() => "isn't a directory anymore",
+                Kind::Glob if i > 0 && !path.is_dir() => "isn't a directory anymore",
+                Kind::Files if path.is_dir() => "is a directory now",
+                Kind::Files if link.file_type().is_symlink() && !path.exists() => "is a broken symlink now",
+                _ => continue,
+            };
+            return Some(format!("\"{}\" {}", redact::path(path), problem));
+        }
+        None
+    }
+
+    /// Why the entry has to be put off for now, if one of its requirements isn't met.
+    pub fn unmet(&self) -> Option<String> {
+        self.requires.iter().filter_map(Requirement::unmet).next()
+    }
+
+    /// The path every copy has to come from, if the entry only syncs one way.
+    pub fn source(&self) -> Option<usize> {
+        match self.direction {
+            Direction::Both => self.master,
+            Direction::LeftToRight => Some(0),
+            Direction::RightToLeft => Some(self.paths.len() - 1),
+        }
+    }
+
+    /// The options the entry was configured with, the way the config spells them, leaving out
+    /// ones that are as good as unset.
+    pub fn options(&self) -> JSONValue {
+        let mut options = json!({
+            "kind": match self.kind { Kind::Files => "files", Kind::Directories => "directories", Kind::Glob => "glob" },
+            "direction": match self.direction { Direction::Both => "both", Direction::LeftToRight => "ltr", Direction::RightToLeft => "rtl" },
+            "compare": match self.compare {
+                Compare::Mtime => "mtime", Compare::Hash => "hash", Compare::Quick => "quick", Compare::Bytes => "bytes",
+            },
+            "on_conflict": match self.on_conflict {
+                OnConflict::Skip => "skip", OnConflict::Newest => "newest", OnConflict::Left => "left",
+                OnConflict::Right => "right", OnConflict::KeepBoth => "keep-both",
+            },
+            "delete": match self.delete { Delete::Off => json!(false), Delete::On => json!(true), Delete::DryRun => json!("dry-run") },
+            "merge": self.merge,
+            "git_guard": self.git_guard,
+            "gitignore": self.gitignore,
+            "vss": self.vss,
+            "priority": self.priority,
+            "policy": self.policy.is_some(),
+            "mtime_tolerance_ms": self.mtime_tolerance.as_millis() as u64,
+        });
+
+        if let Some(ref group) = self.group { options["group_atomic"] = json!(group); }
+        if let Some(i) = self.master { options[if self.golden { "golden" } else { "master" }] = json!(self.paths[i]); }
+        if let Some(ref dir) = self.temp_dir { options["temp_dir"] = json!(dir); }
+        if let Some(ref file) = self.trigger_file { options["trigger_file"] = json!(file); }
+        if let Some(max) = self.max_changes_per_hour { options["max_changes_per_hour"] = json!(max); }
+        if let Some(grace) = self.delete_grace { options["delete_grace"] = json!(grace.as_secs()); }
+        if self.retention.is_set() {
+            options["retention"] = json!({
+                "keep_versions": self.retention.keep_versions,
+                "max_age_days": self.retention.max_age.map(|x| x.as_secs() / 86400),
+                "max_size": self.retention.max_size,
+            });
+        }
+        if let Some(ref command) = self.snapshot_command { options["snapshot_command"] = json!(command); }
+        if let Some(ref command) = self.conflict_command { options["conflict_command"] = json!(command); }
+        if let Some(ref check) = self.verify_replace {
+            options["verify_replace"] = json!({ "max_change": check.max_change, "allow_truncation": check.allow_truncation });
+        }
+        for requirement in &self.requires {
+            let (key, value) = match *requirement {
+                Requirement::Mount(ref path) => ("requires_mount", json!(path)),
+                Requirement::HostUp(ref host) => ("requires_host_up", json!(host)),
+                Requirement::Command(ref name) => ("requires_command", json!(name)),
+            };
+            match options.get_mut(key).and_then(JSONValue::as_array_mut) {
+                Some(list) => list.push(value),
+                None => options[key] = json!([value]),
+            }
+        }
+        if !self.suppress_warnings.is_empty() {
+            let codes: Vec<&str> = self.suppress_warnings.iter().map(|x| x.code()).collect();
+            options["suppress_warnings"] = json!(codes);
+        }
+
+        options
+    }
+
+    /// Directories everything the entry could ever write lives under. Files are written next to
+    /// the ones they replace, so for those it's where they are.
+    pub fn roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = match self.kind {
+            Kind::Directories if !self.pending => self.paths.iter().map(PathBuf::from).collect(),
+            Kind::Glob => vec![glob_base(&self.paths[0]), PathBuf::from(&self.paths[1])],
+            _ => self.paths.iter().map(|x| Path::new(x).parent().unwrap_or(Path::new("/")).to_path_buf()).collect(),
+        };
+        roots.extend(self.temp_dir.clone());
+        roots
+    }
+
+    /// Whether `paths` is one of the pairs this entry could come up with.
+    pub fn covers(&self, paths: &[String]) -> bool {
+        if paths.len() != self.paths.len() { return false }
+        // Could be anything under the paths until they show up.
+        if self.pending { return paths.iter().zip(&self.paths).all(|(x, root)| Path::new(x).starts_with(root)) }
+
+        match self.kind {
+            Kind::Files => paths == &self.paths[..],
+            Kind::Directories => paths.iter().zip(&self.paths).all(|(x, root)| Path::new(x).starts_with(root)),
+            Kind::Glob => glob::Pattern::new(&self.paths[0]).is_ok_and(|x| x.matches(&paths[0]))
+                && Path::new(&paths[1]).starts_with(&self.paths[1]),
+        }
+    }
+
+    fn directory_pairs(&self) -> io::Result<(Pairs, Pairs)> {
+        let gitignore = if self.gitignore { Some(GitIgnore::new(&self.paths)) } else { None };
+        let lists = self.paths.iter().map(|x| walk::files(Path::new(x), &self.exclude, gitignore.as_ref())).collect::<io::Result<Vec<_>>>()?;
+        let sets: Vec<HashSet<&PathBuf>> = lists.iter().map(|x| x.iter().collect()).collect();
+        let mut all: Vec<&PathBuf> = lists.iter().flatten().collect();
+        all.sort();
+        all.dedup();
+
+        let (pairs, lone): (Vec<&PathBuf>, Vec<&PathBuf>) = all.into_iter().partition(|x| sets.iter().all(|y| y.contains(x)));
+        let join = |x: &PathBuf| self.paths.iter().map(|root| Path::new(root).join(x).to_string_lossy().into_owned()).collect();
+        Ok((pairs.into_iter().map(join).collect(), lone.into_iter().map(join).collect()))
+    }
+
+    /// Pairs every file matching the pattern with the file at the same path relative to the
+    /// pattern's base, under the destination directory.
+    fn glob_pairs(&self) -> io::Result<(Pairs, Pairs)> {
+        let base = glob_base(&self.paths[0]);
+        let found = glob::glob(&self.paths[0]).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.msg))?;
+        let mut pairs = Vec::new();
+        let mut lone = Vec::new();
+
+        for from in found {
+            let from = from.map_err(io::Error::from)?;
+            if !from.is_file() || artifacts::is_artifact(&from) { continue }
+
+            let relative = from.strip_prefix(&base).unwrap_or(&from);
+            if self.exclude.excludes(relative, false) { continue }
+
+            let to = Path::new(&self.paths[1]).join(relative);
+            let pair = vec![from.to_string_lossy().into_owned(), to.to_string_lossy().into_owned()];
+            if to.is_file() {
+                pairs.push(pair);
+            } else if !to.exists() {
+                lone.push(pair);
+            }
+        }
+
+        Ok((pairs, lone))
+    }
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// The part of a glob pattern before the first component with a wildcard.
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern).components().take_while(|x| !is_glob(&x.as_os_str().to_string_lossy())).collect()
+}
+
+/// Whether there's nothing to sync from, like when the drive the paths are on isn't plugged in.
+fn unavailable(paths: &[String]) -> bool {
+    if is_glob(&paths[0]) {
+        !glob_base(&paths[0]).exists() || !Path::new(&paths[1]).exists()
+    } else {
+        paths.iter().all(|x| !Path::new(x).exists())
+    }
+}
+
+fn kind_of(paths: &[String]) -> Kind {
+    if is_glob(&paths[0]) {
+        Kind::Glob
+    } else if paths.iter().map(Path::new).find(|x| x.exists()).is_some_and(|x| x.is_dir()) {
+        Kind::Directories
+    } else {
+        Kind::Files
+    }
+}
+
+pub struct Config {
+    pub path: PathBuf,
+    /// Hash of the parsed config, so formatting changes don't count as a different config.
+    pub hash: String,
+    /// Overrides the delay between checks given on the command line.
+    pub delay: Option<Duration>,
+    pub entries: Vec<Entry>,
+    /// Nothing is ever written under these, whatever the entries say.
+    pub protected_paths: Vec<PathBuf>,
+    /// Files under these may only be synced from, never to.
+    pub read_only_roots: Vec<PathBuf>,
+    pub pause_windows: Vec<PauseWindow>,
+    /// How often the daemon re-hashes some pairs that are in sync, to catch silent corruption.
+    pub verify_interval: Option<Duration>,
+    /// How many pairs are re-hashed each time.
+    pub verify_sample: usize,
+    /// File the key encrypting the sync state is derived from.
+    pub state_key: Option<PathBuf>,
+    /// Salt for the pseudonyms paths are logged under, if they're hidden.
+    pub redact_paths: Option<String>,
+    /// Whether the daemon locks itself out of everything but the files it syncs.
+    pub sandbox: bool,
+    /// Whether what staticsync keeps around is left out of Time Machine and Spotlight.
+    pub exclude_from_backups: bool,
+    /// While this file is there, nothing is synced.
+    pub stop_file: Option<PathBuf>,
+    /// What files are hashed with.
+    pub hash_algorithm: Algorithm,
+    /// Warnings not worth giving at all.
+    pub suppress_warnings: Vec<Warning>,
+    /// Retention rules for every entry, and for the history of pairs no entry has anymore.
+    pub retention: Retention,
+}
+
+impl Config {
+    /// Works out what entries that were pending are, now that their paths showed up. Returns the
+    /// ones that turned out not to make sense, those stay pending.
+    pub fn resolve(&mut self) -> Vec<(usize, SetupError)> {
+        let mut problems = Vec::new();
+
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            if !entry.pending || entry.unavailable() { continue }
+
+            entry.kind = kind_of(&entry.paths);
+            entry.pending = false;
+            if let Err(e) = validate(entry) {
+                entry.pending = true;
+                problems.push((i, e));
+            }
+        }
+
+        problems
+    }
+
+    /// Tells why `path` must not be written to, if it mustn't.
+    pub fn write_refusal(&self, path: &Path) -> Option<String> {
+        let canonical = path.canonicalize().ok();
+        let under = |root: &PathBuf| {
+            path.starts_with(root) || canonical.as_ref().is_some_and(|x| x.starts_with(root))
+        };
+
+        if let Some(root) = self.protected_paths.iter().find(|x| under(x)) {
+            return Some(format!("\"{}\" is protected", redact::path(root)));
+        }
+
+        if let Some(root) = self.read_only_roots.iter().find(|x| under(x)) {
+            return Some(format!("\"{}\" is read-only", redact::path(root)));
+        }
+
+        None
+    }
+
+    pub fn covers(&self, paths: &[String]) -> bool {
+        self.entries.iter().any(|x| x.covers(paths))
+    }
+
+    /// The pause window keeping `entry` from being synced at `now`, if any.
+    pub fn pause(&self, entry: &Entry, now: WeekTime) -> Option<&PauseWindow> {
+        self.pause_windows.iter().find(|x| x.applies_to(&entry.tags) && x.active_at(now))
+    }
+}
+
+fn config_error(msg: String) -> SetupError {
+    SetupError::ConfigLoadError(msg)
+}
+
+fn parse_paths(value: &JSONValue) -> Option<Vec<String>> {
+    let paths: Vec<String> = match value.as_array() {
+        Some(a) if a.len() >= 2 => a.iter().filter_map(|x| x.as_str().map(String::from)).collect(),
+        _ => Vec::new(),
+    };
+
+    if paths.len() >= 2 && paths.len() == value.as_array().map_or(0, |x| x.len()) { Some(paths) } else { None }
+}
+
+fn parse_bool(value: &JSONValue, key: &str) -> Result<bool, SetupError> {
+    match value.get(key) {
+        Some(v) => v.as_bool().ok_or_else(|| config_error(format!("\"{}\" must be true or false", key))),
+        None => Ok(false),
+    }
+}
+
+fn parse_path(value: &JSONValue, key: &str) -> Result<Option<PathBuf>, SetupError> {
+    let path = match value.get(key) {
+        Some(v) => PathBuf::from(v.as_str().ok_or_else(|| config_error(format!("\"{}\" must be a path", key)))?),
+        None => return Ok(None),
+    };
+
+    if !path.is_absolute() {
+        return Err(config_error(format!("Path must be absolute: {}", path.display())));
+    }
+
+    Ok(Some(path))
+}
+
+fn parse_dir(value: &JSONValue, key: &str) -> Result<Option<PathBuf>, SetupError> {
+    let dir = match parse_path(value, key)? {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+
+    if !dir.is_dir() {
+        return Err(config_error(format!("\"{}\" is not a directory", dir.display())));
+    }
+
+    Ok(Some(dir))
+}
+
+fn parse_string(value: &JSONValue, key: &str) -> Result<Option<String>, SetupError> {
+    match value.get(key) {
+        Some(v) => v.as_str().map(|x| Some(x.to_string()))
+            .ok_or_else(|| config_error(format!("\"{}\" must be a string", key))),
+        None => Ok(None),
+    }
+}
+
+fn parse_strings(value: &JSONValue, key: &str) -> Result<Vec<String>, SetupError> {
+    let items = match value.get(key) {
+        Some(v) => v.as_array().ok_or_else(|| config_error(format!("\"{}\" must be a list", key)))?,
+        None => return Ok(Vec::new()),
+    };
+
+    items.iter()
+        .map(|x| x.as_str().map(String::from).ok_or_else(|| config_error(format!("\"{}\" must be a list of strings", key))))
+        .collect()
+}
+
+/// A list of strings, where a single one doesn't have to be put in a list.
+fn parse_one_or_more(value: &JSONValue, key: &str) -> Result<Vec<String>, SetupError> {
+    match value.get(key) {
+        Some(JSONValue::String(s)) => Ok(vec![s.clone()]),
+        _ => parse_strings(value, key),
+    }
+}
+
+fn parse_requirements(value: &JSONValue) -> Result<Vec<Requirement>, SetupError> {
+    let mut requires = Vec::new();
+
+    // What `requires_mount` used to be called, configs that still say so mustn't lose the check.
+    let mounts = parse_one_or_more(value, "require_mount")?.into_iter().chain(parse_one_or_more(value, "requires_mount")?);
+    for path in mounts {
+        if !Path::new(&path).is_absolute() {
+            return Err(config_error(format!("Path must be absolute: {}", path)));
+        }
+        requires.push(Requirement::Mount(PathBuf::from(path)));
+    }
+    requires.extend(parse_one_or_more(value, "requires_host_up")?.into_iter().map(Requirement::HostUp));
+    requires.extend(parse_one_or_more(value, "requires_command")?.into_iter().map(Requirement::Command));
+
+    Ok(requires)
+}
+
+fn parse_warnings(value: &JSONValue) -> Result<Vec<Warning>, SetupError> {
+    parse_strings(value, "suppress_warnings")?.iter()
+        .map(|x| Warning::from_code(x).ok_or_else(|| config_error(format!("Unknown warning code in \"suppress_warnings\": {}", x))))
+        .collect()
+}
+
+fn parse_exclude(patterns: &[String]) -> Result<Exclude, SetupError> {
+    Exclude::new(patterns).map_err(|e| config_error(format!("Invalid exclude pattern: {}", e)))
+}
+
+fn parse_policy(value: &JSONValue) -> Result<Option<Policy>, SetupError> {
+    let source = match value.get("policy") {
+        Some(v) => v.as_str().ok_or_else(|| config_error("\"policy\" must be a string".to_string()))?,
+        None => return Ok(None),
+    };
+
+    Policy::compile(source).map(Some).map_err(|e| config_error(format!("Invalid policy \"{}\": {}", source, e)))
+}
+
+fn parse_replace_check(value: &JSONValue) -> Result<Option<ReplaceCheck>, SetupError> {
+    let check = match value.get("verify_replace") {
+        Some(v) if v.is_object() => v,
+        Some(_) => return Err(config_error("\"verify_replace\" must be an object".to_string())),
+        None => return Ok(None),
+    };
+
+    let max_change = match check.get("max_change") {
+        Some(v) => match v.as_f64() {
+            Some(x) if (0.0..=1.0).contains(&x) => Some(x),
+            _ => return Err(config_error("\"max_change\" must be a number between 0 and 1".to_string())),
+        },
+        None => None,
+    };
+
+    Ok(Some(ReplaceCheck { max_change, allow_truncation: parse_bool(check, "allow_truncation")? }))
+}
+
+/// The `retention` rules in `value`, falling back on `base` for the ones it doesn't set.
+fn parse_retention(value: &JSONValue, base: Retention) -> Result<Retention, SetupError> {
+    let rules = match value.get("retention") {
+        Some(v) if v.is_object() => v,
+        Some(_) => return Err(config_error("\"retention\" must be an object".to_string())),
+        None => return Ok(base),
+    };
+    let number = |key: &str| match rules.get(key) {
+        Some(v) => v.as_u64().map(Some).ok_or_else(|| config_error(format!("\"{}\" must be a number", key))),
+        None => Ok(None),
+    };
+
+    Ok(Retention {
+        keep_versions: number("keep_versions")?.map(|x| x as usize).or(base.keep_versions),
+        max_age: number("max_age_days")?.map(|x| Duration::from_secs(x * 86400)).or(base.max_age),
+        max_size: number("max_size")?.or(base.max_size),
+    })
+}
+
+/// Entries are either a bare pair of paths or an object with the paths and their options. Either
+/// way, they leave out the files matched by the config-wide `exclude` patterns and go by the
+/// config-wide `retention` rules, and objects can add patterns and rules of their own.
+fn parse_entry(value: &JSONValue, exclude: &[String], retention: Retention) -> Result<Entry, SetupError> {
+    let paths = match value.get("paths") {
+        Some(p) => parse_paths(p),
+        None => parse_paths(value),
+    };
+
+    let paths = match paths {
+        Some(p) => p,
+        None => return Err(config_error(format!("Entries must be a list of two or more paths: {}", value))),
+    };
+
+    let pending = unavailable(&paths);
+
+    if value.is_array() {
+        let kind = kind_of(&paths);
+        return Ok(Entry {
+            name: None,
+            paths,
+            kind,
+            git_guard: false,
+            vss: false,
+            temp_dir: None,
+            policy: None,
+            group: None,
+            tags: Vec::new(),
+            priority: 0,
+            max_changes_per_hour: None,
+            trigger_file: None,
+            exclude: parse_exclude(exclude)?,
+            gitignore: false,
+            delete: Delete::Off,
+            delete_grace: None,
+            retention,
+            on_conflict: OnConflict::Skip,
+            merge: false,
+            conflict_command: None,
+            direction: Direction::Both,
+            master: None,
+            golden: false,
+            compare: Compare::Mtime,
+            mtime_tolerance: Duration::ZERO,
+            verify_replace: None,
+            snapshot_command: None,
+            requires: Vec::new(),
+            suppress_warnings: Vec::new(),
+            pending,
+        });
+    }
+
+    let golden = value.get("golden").is_some();
+    if golden && value.get("master").is_some() {
+        return Err(config_error(format!("\"golden\" is already the master: {}", paths.join(", "))));
+    }
+    let key = if golden { "golden" } else { "master" };
+    let master = match value.get(key) {
+        Some(v) => match v.as_str().and_then(|x| paths.iter().position(|y| y == x)) {
+            Some(i) => Some(i),
+            None => return Err(config_error(format!("\"{}\" must be one of the paths: {}", key, v))),
+        },
+        None => None,
+    };
+
+    Ok(Entry {
+        name: parse_string(value, "name")?,
+        kind: kind_of(&paths),
+        paths,
+        git_guard: parse_bool(value, "git_guard")?,
+        vss: parse_bool(value, "vss")?,
+        temp_dir: parse_dir(value, "temp_dir")?,
+        policy: parse_policy(value)?,
+        group: parse_string(value, "group_atomic")?,
+        tags: parse_strings(value, "tags")?,
+        priority: match value.get("priority") {
+            Some(v) => v.as_i64().ok_or_else(|| config_error("\"priority\" must be a whole number".to_string()))?,
+            None => 0,
+        },
+        max_changes_per_hour: match value.get("max_changes_per_hour") {
+            Some(v) => Some(v.as_u64().ok_or_else(|| config_error("\"max_changes_per_hour\" must be a number".to_string()))?),
+            None => None,
+        },
+        trigger_file: parse_path(value, "trigger_file")?,
+        exclude: parse_exclude(&[exclude, &parse_strings(value, "exclude")?].concat())?,
+        gitignore: parse_bool(value, "gitignore")?,
+        delete: match value.get("delete") {
+            None | Some(JSONValue::Bool(false)) => Delete::Off,
+            Some(JSONValue::Bool(true)) => Delete::On,
+            Some(v) if v == "dry-run" => Delete::DryRun,
+            Some(_) => return Err(config_error("\"delete\" must be true, false or \"dry-run\"".to_string())),
+        },
+        delete_grace: parse_seconds(value, "delete_grace")?,
+        retention: parse_retention(value, retention)?,
+        on_conflict: match value.get("on_conflict").map(|x| x.as_str()) {
+            None | Some(Some("skip")) => OnConflict::Skip,
+            Some(Some("newest")) => OnConflict::Newest,
+            Some(Some("left")) => OnConflict::Left,
+            Some(Some("right")) => OnConflict::Right,
+            Some(Some("keep-both")) => OnConflict::KeepBoth,
+            Some(_) => return Err(config_error("\"on_conflict\" must be \"skip\", \"newest\", \"left\", \"right\" or \"keep-both\"".to_string())),
+        },
+        merge: parse_bool(value, "merge")?,
+        conflict_command: parse_string(value, "conflict_command")?,
+        direction: match value.get("direction").map(|x| x.as_str()) {
+            None | Some(Some("both")) => Direction::Both,
+            Some(Some("ltr")) => Direction::LeftToRight,
+            Some(Some("rtl")) => Direction::RightToLeft,
+            Some(_) => return Err(config_error("\"direction\" must be \"both\", \"ltr\" or \"rtl\"".to_string())),
+        },
+        master,
+        golden,
+        compare: match value.get("compare").map(|x| x.as_str()) {
+            None | Some(Some("mtime")) => Compare::Mtime,
+            Some(Some("hash")) => Compare::Hash,
+            Some(Some("quick")) => Compare::Quick,
+            Some(Some("bytes")) => Compare::Bytes,
+            Some(_) => return Err(config_error("\"compare\" must be \"mtime\", \"hash\", \"quick\" or \"bytes\"".to_string())),
+        },
+        mtime_tolerance: match value.get("mtime_tolerance_ms") {
+            Some(v) => Duration::from_millis(v.as_u64().ok_or_else(|| config_error("\"mtime_tolerance_ms\" must be a number of milliseconds".to_string()))?),
+            None => Duration::ZERO,
+        },
+        verify_replace: parse_replace_check(value)?,
+        snapshot_command: parse_string(value, "snapshot_command")?,
+        requires: parse_requirements(value)?,
+        suppress_warnings: parse_warnings(value)?,
+        pending,
+    })
+}
+
+/// An absolute path, or one starting with `~/` for the home directory.
+fn home_path(s: &str) -> Result<PathBuf, SetupError> {
+    let path = match s.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+        None => PathBuf::from(s),
+    };
+
+    if !path.is_absolute() {
+        return Err(config_error(format!("Path must be absolute: {}", s)));
+    }
+
+    Ok(path)
+}
+
+fn parse_roots(value: &JSONValue, key: &str) -> Result<Vec<PathBuf>, SetupError> {
+    let list = match value.get(key) {
+        Some(l) => l.as_array().ok_or_else(|| config_error(format!("\"{}\" must be a list of paths", key)))?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut roots = Vec::new();
+    for item in list {
+        let s = item.as_str().ok_or_else(|| config_error(format!("\"{}\" must be a list of paths", key)))?;
+        roots.push(home_path(s)?);
+    }
+
+    Ok(roots)
+}
+
+fn parse_seconds(value: &JSONValue, key: &str) -> Result<Option<Duration>, SetupError> {
+    match value.get(key) {
+        Some(d) => match d.as_u64() {
+            Some(secs) => Ok(Some(Duration::from_secs(secs))),
+            None => Err(config_error(format!("\"{}\" must be a number of seconds", key))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_windows(value: &JSONValue) -> Result<Vec<PauseWindow>, SetupError> {
+    let items = match value.get("pause_windows") {
+        Some(v) => v.as_array().ok_or_else(|| config_error("\"pause_windows\" must be a list".to_string()))?,
+        None => return Ok(Vec::new()),
+    };
+
+    items.iter().map(|x| schedule::parse_window(x).map_err(config_error)).collect()
+}
+
+fn validate(entry: &Entry) -> Result<(), SetupError> {
+    let same_error = |x: &Path| { config_error(format!("Duplicated path: {}", x.display())) };
+    let dir_error = |x: &Path| { config_error(format!("Path \"{}\" is a directory, but not the others!", x.display())) };
+    let nested_error = |x: &Path| { config_error(format!("Directory \"{}\" is inside another one!", x.display())) };
+    let abs_error = |x: &Path| { config_error(format!("Path must be absolute: {}", x.display())) };
+    let excluded_error = |x: &Path| { config_error(format!("Path \"{}\" is excluded", x.display())) };
+    let artifact_error = |x: &Path| { config_error(format!("Path \"{}\" is one of staticsync's own temp files", x.display())) };
+    let path: Vec<&Path> = entry.paths.iter().map(Path::new).collect();
+
+    // Check if paths are absolute
+    if let Some(x) = path.iter().find(|x| !x.is_absolute()) { return Err(abs_error(x)); }
+
+    // Check the rest once there's something to check
+    if entry.pending { return Ok(()); }
+
+    // Check if deletions can be told apart from files that were never there
+    if entry.delete != Delete::Off && entry.kind != Kind::Directories {
+        return Err(config_error(format!("\"delete\" only works with directories: {}", entry.paths.join(", "))));
+    }
+
+    // Check if there are directories to find .gitignore files in
+    if entry.gitignore && entry.kind != Kind::Directories {
+        return Err(config_error(format!("\"gitignore\" only works with directories: {}", entry.paths.join(", "))));
+    }
+
+    // Check if there's anything to put off
+    if entry.delete_grace.is_some() && entry.delete == Delete::Off {
+        return Err(config_error(format!("\"delete_grace\" needs \"delete\": {}", entry.paths.join(", "))));
+    }
+
+    // Check if there's a left and a right
+    if matches!(entry.on_conflict, OnConflict::Left | OnConflict::Right) && path.len() != 2 {
+        return Err(config_error(format!("\"on_conflict\" can only pick a side with two paths: {}", entry.paths.join(", "))));
+    }
+
+    // Check if there's only one say in which way files go
+    if entry.master.is_some() && entry.direction != Direction::Both {
+        return Err(config_error(format!("\"master\" already decides which way files go: {}", entry.paths.join(", "))));
+    }
+
+    // Check if a merge would only write where the entry lets files go
+    if entry.merge && entry.direction != Direction::Both {
+        return Err(config_error(format!("\"merge\" writes to every path that changed, it can't go with \"direction\": {}", entry.paths.join(", "))));
+    }
+
+    // Check if the command has a left and a right, both of which it may write to
+    if entry.conflict_command.is_some() && path.len() != 2 {
+        return Err(config_error(format!("\"conflict_command\" merges two paths: {}", entry.paths.join(", "))));
+    }
+    if entry.conflict_command.is_some() && entry.direction != Direction::Both {
+        return Err(config_error(format!("\"conflict_command\" writes to both paths, it can't go with \"direction\": {}", entry.paths.join(", "))));
+    }
+
+    // Check if there's a hash to keep the base of a merge by
+    if entry.merge && matches!(entry.compare, Compare::Quick | Compare::Bytes) {
+        return Err(config_error(format!("\"merge\" needs hashes, it can't compare \"quick\" or \"bytes\": {}", entry.paths.join(", "))));
+    }
+
+    // Check if the pattern makes sense and has somewhere to go
+    if entry.kind == Kind::Glob {
+        if path.len() != 2 {
+            return Err(config_error(format!("Patterns can only be synced into one directory: {}", entry.paths.join(", "))));
+        }
+        if let Err(e) = glob::Pattern::new(&entry.paths[0]) {
+            return Err(config_error(format!("Invalid pattern \"{}\": {}", entry.paths[0], e.msg)));
+        }
+        if !path[1].is_dir() {
+            return Err(config_error(format!("\"{}\" must be a directory to sync \"{}\" into", path[1].display(), path[0].display())));
+        }
+        if path[1].starts_with(glob_base(&entry.paths[0])) { return Err(nested_error(path[1])); }
+        return Ok(());
+    }
+
+    // Check if paths are all directories or all files, as far as they exist
+    let existing: Vec<&Path> = path.iter().filter(|x| x.exists()).cloned().collect();
+    if let Some(x) = existing.iter().find(|x| x.is_dir() != existing[0].is_dir()) {
+        return Err(dir_error(if existing[0].is_dir() { existing[0] } else { x }));
+    }
+
+    // Check if directories contain each other
+    if entry.kind == Kind::Directories {
+        for (a, b) in path.iter().flat_map(|a| path.iter().map(move |b| (a, b))) {
+            if a != b && a.starts_with(b) { return Err(nested_error(a)); }
+        }
+    }
+
+    // Check if paths are our own temp files
+    if let Some(x) = path.iter().find(|x| artifacts::is_artifact(x)) { return Err(artifact_error(x)); }
+
+    // Check if files are excluded, which only makes sense for the ones in directories
+    if entry.kind == Kind::Files {
+        let excluded = |x: &Path| x.file_name().is_some_and(|name| entry.exclude.excludes(Path::new(name), false));
+        if let Some(x) = path.iter().find(|x| excluded(x)) { return Err(excluded_error(x)); }
+    }
+
+    // Check if paths are duplicated
+    for (i, x) in path.iter().enumerate() {
+        if path[..i].contains(x) { return Err(same_error(x)); }
+    }
+
+    Ok(())
+}
+
+/// Loads and validates the config file at `path`.
+pub fn load(path: &Path) -> Result<Config, SetupError> {
+    let value: JSONValue = serde_json::from_reader(File::open(path)?)?;
+    let files = match value.get("files").and_then(|x| x.as_array()) {
+        Some(f) => f,
+        None => return Err(config_error("Missing \"files\" list".to_string())),
+    };
+
+    let delay = parse_seconds(&value, "delay")?;
+
+    let mut hasher = Sha1::new();
+    hasher.input_str(&value.to_string());
+
+    let mut config = Config {
+        path: path.to_path_buf(),
+        hash: hasher.result_str(),
+        delay,
+        entries: Vec::new(),
+        protected_paths: parse_roots(&value, "protected_paths")?,
+        read_only_roots: parse_roots(&value, "read_only_roots")?,
+        pause_windows: parse_windows(&value)?,
+        verify_interval: parse_seconds(&value, "verify_interval")?,
+        verify_sample: match value.get("verify_sample") {
+            Some(n) => n.as_u64().ok_or_else(|| config_error("\"verify_sample\" must be a number".to_string()))? as usize,
+            None => VERIFY_SAMPLE,
+        },
+        state_key: parse_path(&value, "state_key")?,
+        redact_paths: match value.get("redact_paths") {
+            None | Some(JSONValue::Bool(false)) => None,
+            Some(JSONValue::Bool(true)) => Some(String::new()),
+            Some(JSONValue::String(salt)) => Some(salt.clone()),
+            Some(_) => return Err(config_error("\"redact_paths\" must be true, false or a salt".to_string())),
+        },
+        sandbox: parse_bool(&value, "sandbox")?,
+        exclude_from_backups: parse_bool(&value, "exclude_from_backups")?,
+        stop_file: match value.get("stop_file") {
+            Some(v) => Some(home_path(v.as_str().ok_or_else(|| config_error("\"stop_file\" must be a path".to_string()))?)?),
+            None => None,
+        },
+        hash_algorithm: match value.get("hash") {
+            Some(v) => v.as_str().and_then(Algorithm::parse).ok_or_else(|| {
+                config_error(format!("\"hash\" must be one of {}", Algorithm::NAMES.join(", ")))
+            })?,
+            None => Algorithm::default(),
+        },
+        suppress_warnings: parse_warnings(&value)?,
+        retention: parse_retention(&value, Retention::default())?,
+    };
+
+    let exclude = parse_strings(&value, "exclude")?;
+    for file in files {
+        let entry = parse_entry(file, &exclude, config.retention)?;
+        validate(&entry)?;
+
+        // An entry that can't be written anywhere can never be synced, that's surely a mistake.
+        if entry.paths.iter().all(|x| config.write_refusal(Path::new(x)).is_some()) {
+            return Err(config_error(format!("None of the paths in {} can be written to", entry.paths.join(", "))));
+        }
+
+        if let Some(ref name) = entry.name {
+            if config.entries.iter().any(|x| x.name.as_ref() == Some(name)) {
+                return Err(config_error(format!("More than one entry is named \"{}\"", name)));
+            }
+        }
+
+        config.entries.push(entry);
+    }
+
+    // Only once every entry is in, loops can go through any number of them.
+    if !parse_bool(&value, "allow_loops")? {
+        if let Some(problem) = loops::find(&config.entries) {
+            return Err(config_error(format!("Syncing would never settle, {}. Set \"allow_loops\" if that's really what you want", problem)));
+        }
+    }
+
+    Ok(config)
+}
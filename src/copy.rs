@@ -0,0 +1,444 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use artifacts::{CHECKPOINT_SUFFIX, STAGING_SUFFIX, UNDO_SUFFIX};
+use crypto::{digest::Digest, sha1::Sha1};
+use filetime::{self, FileTime};
+use locale::civil_date;
+use serde_json::{self, Value as JSONValue};
+
+/// How much gets copied between making progress durable.
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+pub struct CopyOptions<'a> {
+    /// Files at least this big are copied around the page cache.
+    pub direct_threshold: Option<u64>,
+    /// Where to stage the copy instead of the destination's directory.
+    pub temp_dir: Option<&'a Path>,
+}
+
+/// Called with the destination and how far into it the copy is after every chunk.
+type Progress<'a> = dyn FnMut(&File, u64) -> io::Result<()> + 'a;
+
+/// Copies `from` over `to`, creating it and its directory if needed. Files of at least
+/// `direct_threshold` bytes are copied around the page cache so that a huge transfer doesn't evict
+/// everything else from memory.
+///
+/// The data is written to a staging file next to `to` (or in `temp_dir`) first and only renamed
+/// over it once complete, so a failed copy never leaves a half written destination behind. Big
+/// copies record how far they got every now and then, and an interrupted one picks up from there
+/// next time as long as the source hasn't changed.
+pub fn copy(from: &Path, to: &Path, options: &CopyOptions) -> io::Result<()> {
+    let staging = stage(from, to, options)?;
+    commit(&staging, to)
+}
+
+/// Does all of `copy` except moving the result into place, so several copies can be finished
+/// together. Returns where the staged copy is.
+pub fn stage(from: &Path, to: &Path, options: &CopyOptions) -> io::Result<PathBuf> {
+    if let Some(dir) = to.parent() { fs::create_dir_all(dir)?; }
+    let staging = staging_path(to, options.temp_dir);
+    let checkpoint = with_suffix(&staging, CHECKPOINT_SUFFIX);
+    let meta = fs::metadata(from)?;
+    let mtime = FileTime::from_last_modification_time(&meta);
+    let offset = resume_offset(&staging, &checkpoint, meta.len(), mtime);
+
+    let mut saved = offset;
+    let mut progress = |file: &File, done: u64| -> io::Result<()> {
+        if done < saved + CHECKPOINT_INTERVAL { return Ok(()) }
+        file.sync_data()?;
+        write_checkpoint(&checkpoint, meta.len(), mtime, done)?;
+        saved = done;
+        Ok(())
+    };
+
+    let result = match options.direct_threshold {
+        Some(threshold) if meta.len() >= threshold => direct::copy(from, &staging, offset, &mut progress),
+        _ => zero_copy::copy(from, &staging, offset, &mut progress),
+    };
+
+    match result {
+        Ok(_) => {
+            let _ = fs::remove_file(&checkpoint);
+            Ok(staging)
+        }
+        Err(e) => {
+            // Keep whatever was made durable so the next attempt doesn't start over.
+            if !checkpoint.exists() { let _ = fs::remove_file(&staging); }
+            Err(e)
+        }
+    }
+}
+
+/// Files an interrupted copy over `to` left behind to resume from.
+pub fn leftovers(to: &Path, temp_dir: Option<&Path>) -> Vec<PathBuf> {
+    let staging = staging_path(to, temp_dir);
+    let checkpoint = with_suffix(&staging, CHECKPOINT_SUFFIX);
+    vec![staging, checkpoint].into_iter().filter(|x| x.exists()).collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn staging_path(path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let name = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    match temp_dir {
+        // Files from different directories can share a name in there.
+        Some(dir) => {
+            let mut hasher = Sha1::new();
+            hasher.input_str(&path.to_string_lossy());
+            dir.join(format!(".{}.{}{}", name, &hasher.result_str()[..8], STAGING_SUFFIX))
+        }
+        None => path.with_file_name(format!(".{}{}", name, STAGING_SUFFIX)),
+    }
+}
+
+/// Moves a staged copy into place.
+pub fn commit(staging: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(staging, to) {
+        // A temp dir on another filesystem can't be renamed from, that's the price of using one.
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            fs::copy(staging, to)?;
+            fs::remove_file(staging)
+        }
+        result => result,
+    }
+}
+
+/// Keeps what's at `path` next to it before it's replaced, so `staticsync undo` can put it back.
+/// A hard link does unless the file is written over `in_place`, which copying from a temp dir on
+/// another filesystem does. Returns where it went.
+pub fn back_up(path: &Path, in_place: bool) -> io::Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let backup = path.with_file_name(format!(".{}{}", name, UNDO_SUFFIX));
+    // Left over from a run that didn't get to save its journal.
+    if backup.exists() { fs::remove_file(&backup)?; }
+
+    if in_place || fs::hard_link(path, &backup).is_err() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(backup)
+}
+
+/// Keeps a copy of `path` next to it before it's replaced by the other side of a conflict, named
+/// like `notes.conflict-20240101-laptop.txt` after the day and the machine that kept it. Returns
+/// where it went.
+pub fn keep_conflict(path: &Path) -> io::Result<PathBuf> {
+    let meta = fs::metadata(path)?;
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_date(secs / 86400);
+    let tag = format!("conflict-{:04}{:02}{:02}-{}", year, month, day, hostname());
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|x| format!(".{}", x.to_string_lossy())).unwrap_or_default();
+    let mut kept = path.with_file_name(format!("{}.{}{}", stem, tag, extension));
+    let mut n = 1;
+    while kept.exists() {
+        n += 1;
+        kept = path.with_file_name(format!("{}.{}-{}{}", stem, tag, n, extension));
+    }
+
+    fs::copy(path, &kept)?;
+    filetime::set_file_mtime(&kept, FileTime::from_last_modification_time(&meta))?;
+    Ok(kept)
+}
+
+/// This machine's name without its domain, so copies kept on different machines sharing the
+/// files don't get mixed up.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ok = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0;
+    let len = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
+    let name = if ok { String::from_utf8_lossy(&buf[..len]).into_owned() } else { String::new() };
+
+    match name.split('.').next() {
+        Some(short) if !short.is_empty() => short.replace(['/', '\\'], "_"),
+        _ => "unknown".to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn write_checkpoint(path: &Path, size: u64, mtime: FileTime, offset: u64) -> io::Result<()> {
+    let value = json!({
+        "size": size,
+        "mtime": mtime.unix_seconds(),
+        "mtime_nsec": mtime.nanoseconds(),
+        "offset": offset,
+    });
+    fs::write(path, value.to_string())
+}
+
+/// Where to resume copying into `staging`, 0 if there's nothing usable there.
+fn resume_offset(staging: &Path, checkpoint: &Path, size: u64, mtime: FileTime) -> u64 {
+    let value: Option<JSONValue> = fs::read(checkpoint).ok().and_then(|x| serde_json::from_slice(&x).ok());
+    let field = |key: &str| value.as_ref().and_then(|x| x.get(key)).and_then(|x| x.as_u64());
+    let staged = fs::metadata(staging).map(|x| x.len()).unwrap_or(0);
+
+    let same_source = field("size") == Some(size)
+        && field("mtime") == Some(mtime.unix_seconds() as u64)
+        && field("mtime_nsec") == Some(mtime.nanoseconds() as u64);
+
+    match field("offset") {
+        Some(offset) if same_source && offset <= staged => offset,
+        _ => {
+            let _ = fs::remove_file(checkpoint);
+            0
+        }
+    }
+}
+
+/// Positions both files at `offset`, dropping anything in `dst` past it.
+fn resume_at(src: &mut File, dst: &mut File, offset: u64) -> io::Result<()> {
+    dst.set_len(offset)?;
+    src.seek(SeekFrom::Start(offset))?;
+    dst.seek(SeekFrom::Start(offset))?;
+    Ok(())
+}
+
+/// Reserves `len` bytes for `file` up front. This keeps the copy from fragmenting and turns a
+/// full disk into an immediate error instead of a partial write found halfway through.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    if len == 0 { return Ok(()) }
+
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        // Not every filesystem can do it, that's fine.
+        libc::EOPNOTSUPP | libc::EINVAL => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(unix)]
+fn copy_permissions(from: &Path, to: &Path) -> io::Result<()> {
+    fs::set_permissions(to, fs::metadata(from)?.permissions())
+}
+
+#[cfg(target_os = "linux")]
+mod zero_copy {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use libc;
+
+    // Data never leaves the kernel, but it's still moved in bounded chunks so each syscall stays
+    // short and anything pacing the transfer gets a chance to run between them.
+    const CHUNK: usize = 8 * 1024 * 1024;
+
+    enum Method {
+        CopyFileRange,
+        Sendfile,
+        Userspace,
+    }
+
+    fn fallback(err: &io::Error) -> bool {
+        matches!(err.raw_os_error(),
+            Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL))
+    }
+
+    pub fn copy(from: &Path, to: &Path, offset: u64, progress: &mut Progress) -> io::Result<u64> {
+        let mut src = File::open(from)?;
+        let mut dst = OpenOptions::new().write(true).create(true).truncate(false).open(to)?;
+        resume_at(&mut src, &mut dst, offset)?;
+        preallocate(&dst, src.metadata()?.len())?;
+        let mut method = Method::CopyFileRange;
+        let mut total: u64 = offset;
+
+        loop {
+            let n = match method {
+                Method::CopyFileRange => unsafe {
+                    libc::copy_file_range(src.as_raw_fd(), ptr::null_mut(), dst.as_raw_fd(), ptr::null_mut(), CHUNK, 0)
+                },
+                Method::Sendfile => unsafe {
+                    libc::sendfile(dst.as_raw_fd(), src.as_raw_fd(), ptr::null_mut(), CHUNK)
+                },
+                Method::Userspace => {
+                    // Both file offsets already sit past whatever the kernel copied.
+                    total += io::copy(&mut src, &mut dst)?;
+                    break;
+                }
+            };
+
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                if !fallback(&err) { return Err(err) }
+
+                method = match method {
+                    Method::CopyFileRange => Method::Sendfile,
+                    _ => Method::Userspace,
+                };
+                continue;
+            }
+
+            if n == 0 { break }
+            total += n as u64;
+            progress(&dst, total)?;
+        }
+
+        // Don't keep the reserved space if the source shrank in the meantime.
+        dst.set_len(total)?;
+        copy_permissions(from, to)?;
+        Ok(total)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod zero_copy {
+    use super::*;
+
+    pub fn copy(from: &Path, to: &Path, offset: u64, _progress: &mut Progress) -> io::Result<u64> {
+        // std already uses the platform's in-kernel copy (fcopyfile, CopyFileEx) where there is
+        // one, but it can only start from scratch.
+        if offset == 0 { return fs::copy(from, to) }
+
+        let mut src = File::open(from)?;
+        let mut dst = OpenOptions::new().write(true).create(true).truncate(false).open(to)?;
+        resume_at(&mut src, &mut dst, offset)?;
+        let total = offset + io::copy(&mut src, &mut dst)?;
+        fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+        Ok(total)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod direct {
+    use super::*;
+    use std::alloc::{self, Layout};
+    use std::ops::{Deref, DerefMut};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::slice;
+    use libc;
+
+    // O_DIRECT needs buffers, offsets and lengths aligned to the logical block size. 4 KB covers
+    // every disk we care about.
+    const ALIGN: usize = 4096;
+    const CHUNK: usize = 1024 * 1024;
+
+    struct AlignedBuffer {
+        ptr: *mut u8,
+        layout: Layout,
+    }
+
+    impl AlignedBuffer {
+        fn new(size: usize) -> Self {
+            let layout = Layout::from_size_align(size, ALIGN).unwrap();
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            if ptr.is_null() { alloc::handle_alloc_error(layout); }
+            AlignedBuffer { ptr, layout }
+        }
+    }
+
+    impl Deref for AlignedBuffer {
+        type Target = [u8];
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+        }
+    }
+
+    impl DerefMut for AlignedBuffer {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+        }
+    }
+
+    impl Drop for AlignedBuffer {
+        fn drop(&mut self) {
+            unsafe { alloc::dealloc(self.ptr, self.layout); }
+        }
+    }
+
+    fn unsupported(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(libc::EINVAL)
+    }
+
+    pub fn copy(from: &Path, to: &Path, offset: u64, progress: &mut Progress) -> io::Result<u64> {
+        // Filesystems like tmpfs refuse O_DIRECT at open time, fall back to a regular copy there.
+        let mut src = match OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(from) {
+            Ok(f) => f,
+            Err(ref e) if unsupported(e) => return fs::copy(from, to),
+            Err(e) => return Err(e),
+        };
+        let mut dst = match OpenOptions::new().write(true).create(true).truncate(false)
+            .custom_flags(libc::O_DIRECT).open(to) {
+            Ok(f) => f,
+            Err(ref e) if unsupported(e) => return fs::copy(from, to),
+            Err(e) => return Err(e),
+        };
+
+        // Resuming has to happen on a block boundary as well.
+        let offset = offset / ALIGN as u64 * ALIGN as u64;
+        resume_at(&mut src, &mut dst, offset)?;
+        preallocate(&dst, src.metadata()?.len())?;
+
+        let mut buf = AlignedBuffer::new(CHUNK);
+        let mut total: u64 = offset;
+
+        loop {
+            let mut n = 0;
+            while n < CHUNK {
+                match src.read(&mut buf[n..]) {
+                    Ok(0) => break,
+                    Ok(read) => n += read,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if n == 0 { break }
+
+            // The tail has to be written as a whole block too, it's truncated away afterwards.
+            let padded = n.div_ceil(ALIGN) * ALIGN;
+            for byte in &mut buf[n..padded] { *byte = 0; }
+            dst.write_all(&buf[..padded])?;
+            total += n as u64;
+            progress(&dst, total)?;
+
+            if n < CHUNK { break }
+        }
+
+        dst.set_len(total)?;
+        copy_permissions(from, to)?;
+        Ok(total)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod direct {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    pub fn copy(from: &Path, to: &Path, offset: u64, _progress: &mut Progress) -> io::Result<u64> {
+        let mut src = File::open(from)?;
+        let mut dst = OpenOptions::new().write(true).create(true).truncate(false).open(to)?;
+        resume_at(&mut src, &mut dst, offset)?;
+
+        unsafe {
+            libc::fcntl(src.as_raw_fd(), libc::F_NOCACHE, 1);
+            libc::fcntl(dst.as_raw_fd(), libc::F_NOCACHE, 1);
+        }
+
+        let total = offset + io::copy(&mut src, &mut dst)?;
+        copy_permissions(from, to)?;
+        Ok(total)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod direct {
+    pub use super::zero_copy::copy;
+}
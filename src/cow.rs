@@ -0,0 +1,34 @@
+//! Filesystem snapshots taken before a pass changes anything, so copy-on-write filesystems like
+//! btrfs or ZFS can roll back whatever it did.
+
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use users;
+
+/// Runs the snapshot `command` for `root`, which it gets as `$STATICSYNC_ROOT`.
+pub fn take(command: &str, root: &Path) -> io::Result<()> {
+    let mut shell = shell(command);
+    users::confine(&mut shell);
+    let status = shell.env("STATICSYNC_ROOT", root).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("the snapshot command failed ({})", status)))
+    }
+}
+
+/// `command` run through the system's shell, like every command in the config.
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
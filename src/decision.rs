@@ -0,0 +1,68 @@
+use std::io;
+use std::time::Duration;
+use filetime::FileTime;
+
+/// What should happen to a file of a pair.
+#[derive(Clone, Copy)]
+pub enum Decision {
+    /// All files have the same mtime, or close enough, nothing to do.
+    Unchanged,
+    /// The contents already match, only the older file's mtime needs to catch up.
+    Touch { newest: usize, oldest: usize },
+    /// The newest file replaces the oldest one.
+    Copy { newest: usize, oldest: usize },
+}
+
+impl Decision {
+    pub fn describe(&self) -> String {
+        match self {
+            Decision::Unchanged => "do nothing".to_string(),
+            Decision::Touch { newest, oldest } =>
+                format!("copy mtime of #{} to #{}, contents match", newest + 1, oldest + 1),
+            Decision::Copy { newest, oldest } => format!("replace #{} with #{}", oldest + 1, newest + 1),
+        }
+    }
+}
+
+/// Whether two mtimes are the same, give or take `tolerance` for filesystems that round them.
+pub fn same_mtime(a: FileTime, b: FileTime, tolerance: Duration) -> bool {
+    let nanos = |x: FileTime| x.unix_seconds() as i128 * 1_000_000_000 + x.nanoseconds() as i128;
+    (nanos(a) - nanos(b)).unsigned_abs() <= tolerance.as_nanos()
+}
+
+/// Decides what to do with a pair given their mtimes: one decision for every file that doesn't
+/// have the newest mtime, within `tolerance`, or the mtime of the `master` if there is one,
+/// whatever the others are. `same_contents(newest, oldest)` is only called for those, since
+/// finding out is expensive.
+pub fn decide<F>(mtime: &[FileTime], master: Option<usize>, tolerance: Duration, mut same_contents: F) -> io::Result<Vec<Decision>>
+    where F: FnMut(usize, usize) -> io::Result<bool> {
+    let newest = master.unwrap_or_else(|| (0..mtime.len()).max_by_key(|&i| mtime[i]).unwrap_or(0));
+    let behind: Vec<usize> = (0..mtime.len()).filter(|&i| !same_mtime(mtime[i], mtime[newest], tolerance)).collect();
+    if behind.is_empty() { return Ok(vec![Decision::Unchanged]) }
+
+    behind.into_iter().map(|oldest| {
+        if same_contents(newest, oldest)? {
+            Ok(Decision::Touch { newest, oldest })
+        } else {
+            Ok(Decision::Copy { newest, oldest })
+        }
+    }).collect()
+}
+
+/// Decides what to do with a pair by contents alone, given the `hash` of every file and the one
+/// they all had when they were last in sync. The file that changed since then replaces the others,
+/// or the `master` if there is one. When several did, or nothing is known about the last sync, the
+/// one with the newest `mtime` is picked, conflicts are up to the caller.
+pub fn decide_by_hash(hash: &[Option<String>], synced: Option<&str>, master: Option<usize>, mtime: &[FileTime]) -> Vec<Decision> {
+    let changed: Vec<usize> = (0..hash.len())
+        .filter(|&i| hash[i].is_some() && hash[i].as_deref() != synced).collect();
+    let newest = master
+        .or_else(|| changed.iter().cloned().max_by_key(|&i| mtime[i]))
+        .or_else(|| (0..hash.len()).find(|&i| hash[i].is_some()))
+        .unwrap_or(0);
+
+    let behind: Vec<Decision> = (0..hash.len())
+        .filter(|&i| i != newest && (hash[i].is_none() || hash[i] != hash[newest]))
+        .map(|oldest| Decision::Copy { newest, oldest }).collect();
+    if behind.is_empty() { vec![Decision::Unchanged] } else { behind }
+}
@@ -0,0 +1,90 @@
+//! Rough measure of how much a file changes when it's replaced, to catch replacements that look
+//! more like damage than like edits before they spread.
+
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use crypto::{digest::Digest, sha1::Sha1};
+use config::ReplaceCheck;
+
+/// Files are compared in blocks this big, the way most filesystems store them.
+const BLOCK: usize = 4096;
+
+pub struct Change {
+    /// Share of the blocks of the bigger file that aren't anywhere in the other one.
+    pub fraction: f64,
+    /// Whether the new file is the start of the old one and nothing else.
+    pub truncation: bool,
+}
+
+/// Calls `each` with every block of the file at `path`.
+fn blocks<F: FnMut(&[u8])>(path: &Path, mut each: F) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; BLOCK];
+
+    loop {
+        let mut n = 0;
+        while n < BLOCK {
+            let read = file.read(&mut buf[n..])?;
+            if read == 0 { break }
+            n += read;
+        }
+        if n > 0 { each(&buf[..n]); }
+        if n < BLOCK { return Ok(()) }
+    }
+}
+
+fn digest(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    let mut out = [0u8; 20];
+    hasher.input(data);
+    hasher.result(&mut out);
+    out
+}
+
+/// How the file at `new` differs from the one at `old`, going by block hashes.
+pub fn estimate(old: &Path, new: &Path) -> io::Result<Change> {
+    let new_len = fs::metadata(new)?.len() as usize;
+
+    let mut old_blocks = HashSet::new();
+    let mut old_count = 0;
+    let mut old_len = 0;
+    // The start of the old file, as much of it as the new one is long.
+    let mut prefix = Sha1::new();
+    blocks(old, |block| {
+        old_blocks.insert(digest(block));
+        old_count += 1;
+        prefix.input(&block[..min(block.len(), new_len.saturating_sub(old_len))]);
+        old_len += block.len();
+    })?;
+
+    let mut new_count = 0;
+    let mut matching = 0;
+    let mut whole = Sha1::new();
+    blocks(new, |block| {
+        if old_blocks.contains(&digest(block)) { matching += 1; }
+        new_count += 1;
+        whole.input(block);
+    })?;
+
+    let total = max(old_count, new_count);
+    Ok(Change {
+        fraction: if total == 0 { 0.0 } else { 1.0 - matching as f64 / total as f64 },
+        truncation: new_len < old_len && prefix.result_str() == whole.result_str(),
+    })
+}
+
+/// Why replacing `old` with `new` looks wrong to `check`, if it does.
+pub fn suspicious(check: &ReplaceCheck, old: &Path, new: &Path) -> io::Result<Option<String>> {
+    let change = estimate(old, new)?;
+
+    if change.truncation && !check.allow_truncation {
+        return Ok(Some("the new version is only the start of it".to_string()));
+    }
+    match check.max_change {
+        Some(limit) if change.fraction > limit => Ok(Some(format!("{:.0}% of it would change", change.fraction * 100.0))),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,96 @@
+//! Gitignore-style patterns for files that are never synced, and the `.gitignore` files of the
+//! work trees being synced.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+pub struct Exclude {
+    matcher: Gitignore,
+}
+
+impl Exclude {
+    /// Builds a matcher from gitignore lines like `*.tmp`, `.git/` or `!keep.tmp`.
+    pub fn new(patterns: &[String]) -> Result<Exclude, String> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in patterns {
+            builder.add_line(None, pattern).map_err(|e| e.to_string())?;
+        }
+
+        Ok(Exclude { matcher: builder.build().map_err(|e| e.to_string())? })
+    }
+
+    /// Whether `path`, relative to the directory being synced, is left out. Anything inside an
+    /// excluded directory is too.
+    pub fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        self.matcher.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+/// What git ignores on the sides of a directory entry that are in a work tree. A file ignored on
+/// any side is left out on all of them, or it would be created again where it's ignored on every
+/// pass.
+pub struct GitIgnore {
+    sides: Vec<Side>,
+    /// The `.gitignore` of every directory looked at so far, if it has one.
+    loaded: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+struct Side {
+    root: PathBuf,
+    /// The top of the work tree `root` is in.
+    top: PathBuf,
+    /// Its `.git/info/exclude`.
+    info: Option<Gitignore>,
+}
+
+impl GitIgnore {
+    /// Finds the work trees `roots` are in, if any. Sides that aren't in one ignore nothing, like
+    /// git would.
+    pub fn new(roots: &[String]) -> GitIgnore {
+        let sides = roots.iter().filter_map(|root| {
+            let root = Path::new(root);
+            let top = root.ancestors().find(|x| x.join(".git").exists())?;
+            Some(Side { root: root.to_path_buf(), top: top.to_path_buf(), info: read(top, &top.join(".git/info/exclude")) })
+        }).collect();
+
+        GitIgnore { sides, loaded: RefCell::new(HashMap::new()) }
+    }
+
+    /// Whether `path`, relative to the directories being synced, is ignored on any side. Anything
+    /// inside an ignored directory is too.
+    pub fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        self.sides.iter().any(|side| self.ignored(side, &side.root.join(path), is_dir))
+    }
+
+    /// Goes the way git does: the closest `.gitignore` has the last word, then the ones above it up
+    /// to the top of the work tree, then `.git/info/exclude`.
+    fn ignored(&self, side: &Side, path: &Path, is_dir: bool) -> bool {
+        let mut loaded = self.loaded.borrow_mut();
+        for dir in path.ancestors().skip(1).take_while(|x| x.starts_with(&side.top)) {
+            let matcher = loaded.entry(dir.to_path_buf()).or_insert_with(|| read(dir, &dir.join(".gitignore")));
+            if let Some(ignored) = matcher.as_ref().and_then(|x| verdict(x, path, is_dir)) { return ignored }
+        }
+        side.info.as_ref().and_then(|x| verdict(x, path, is_dir)).unwrap_or(false)
+    }
+}
+
+/// The patterns in `file`, relative to `dir`, leaving out lines that don't parse like git does.
+/// None if there's no such file.
+fn read(dir: &Path, file: &Path) -> Option<Gitignore> {
+    if !file.is_file() { return None }
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(file);
+    builder.build().ok()
+}
+
+/// Whether `matcher` ignores `path` or brings it back with `!`, if it says anything about it.
+fn verdict(matcher: &Gitignore, path: &Path, is_dir: bool) -> Option<bool> {
+    match matcher.matched_path_or_any_parents(path, is_dir) {
+        Match::None => None,
+        Match::Ignore(_) => Some(true),
+        Match::Whitelist(_) => Some(false),
+    }
+}
@@ -0,0 +1,151 @@
+use std::io;
+use std::path::Path;
+use filetime::FileTime;
+use serde_json::Value as JSONValue;
+use config::{as_strs, Compare, Config, Entry, OnConflict};
+use decision::{Decision, decide, decide_by_hash};
+use delta;
+use git;
+use policy::Verdict;
+use state::PairState;
+
+/// Everything that went into deciding what to do with a pair.
+pub struct Explanation {
+    pub paths: Vec<String>,
+    pub mtime: Vec<FileTime>,
+    pub size: Vec<u64>,
+    /// Files that aren't there get created from the newest one.
+    pub exists: Vec<bool>,
+    /// Only filled in when the mtimes differ or the entry compares by hash, like in a real pass.
+    /// Files that aren't there have none.
+    pub hash: Option<Vec<Option<String>>>,
+    pub decisions: Vec<Decision>,
+    /// Why each decision won't be carried out, if it won't.
+    pub blocked_by: Vec<Option<String>>,
+}
+
+/// Works out what a pass would do with `paths`, one of the pairs of `entry`, without touching
+/// anything.
+pub fn explain(config: &Config, entry: &Entry, paths: &[String], pair: Option<&PairState>, buffer_size: usize) -> io::Result<Explanation> {
+    let path = as_strs(paths);
+    let meta = ::stat(&path)?;
+    let mtime = ::mtimes(&meta);
+    let size: Vec<u64> = meta.iter().map(|x| x.as_ref().map_or(0, |m| m.len())).collect();
+    let exists: Vec<bool> = meta.iter().map(Option::is_some).collect();
+
+    let mut hash: Option<Vec<Option<String>>> = None;
+    let decisions = match entry.compare {
+        Compare::Hash => {
+            let h = ::hashes(config.hash_algorithm, buffer_size, &path, &meta)?;
+            let decisions = decide_by_hash(&h, pair.and_then(|x| x.synced_hash.as_deref()), entry.master, &mtime);
+            hash = Some(h);
+            decisions
+        }
+        Compare::Mtime => decide(&mtime, entry.master, entry.mtime_tolerance, |newest, oldest| {
+            if meta[oldest].is_none() { return Ok(false) }
+            if hash.is_none() { hash = Some(::hashes(config.hash_algorithm, buffer_size, &path, &meta)?); }
+            let h = hash.as_ref().unwrap();
+            Ok(h[newest] == h[oldest])
+        })?,
+        Compare::Quick => decide(&mtime, entry.master, entry.mtime_tolerance, |_, _| Ok(false))?,
+        Compare::Bytes => decide(&mtime, entry.master, entry.mtime_tolerance, |newest, oldest| {
+            ::same_contents(buffer_size, path[newest], path[oldest])
+        })?,
+    };
+
+    let blocked_by = decisions.iter().map(|&decision| match decision {
+        Decision::Unchanged => None,
+        Decision::Touch { oldest, .. } => config.write_refusal(Path::new(path[oldest])),
+        Decision::Copy { newest, oldest } => config.write_refusal(Path::new(path[oldest])).or_else(|| {
+            if entry.master.is_none() && entry.on_conflict == OnConflict::Skip && ::conflicting(entry, pair, &mtime, hash.as_deref(), newest, oldest) {
+                return Some(format!("{} changed since the last sync too", path[oldest]));
+            }
+            if entry.source().is_some_and(|x| x != newest) {
+                return Some(format!("the entry only syncs from {}", path[entry.source().unwrap()]));
+            }
+            if entry.git_guard && git::has_uncommitted_changes(Path::new(path[oldest])) {
+                return Some(format!("{} has uncommitted changes", path[oldest]));
+            }
+
+            if let Some(ref policy) = entry.policy {
+                match policy.evaluate(&path, &size, &mtime, newest, oldest) {
+                    Ok(Verdict::Copy) => {}
+                    Ok(Verdict::Skip) => return Some("policy skips it".to_string()),
+                    Ok(Verdict::Conflict) => return Some("policy flagged a conflict".to_string()),
+                    Err(e) => return Some(e),
+                }
+            }
+
+            let check = entry.verify_replace.as_ref().filter(|_| exists[oldest])?;
+            match delta::suspicious(check, Path::new(path[oldest]), Path::new(path[newest])) {
+                Ok(reason) => reason.map(|x| format!("{}: {}", path[oldest], x)),
+                Err(e) => Some(format!("couldn't check the replacement: {}", e)),
+            }
+        }),
+    }).collect();
+
+    Ok(Explanation { paths: paths.to_vec(), mtime, size, exists, hash, decisions, blocked_by })
+}
+
+impl Explanation {
+    pub fn to_json(&self) -> JSONValue {
+        let files: Vec<JSONValue> = (0..self.paths.len()).map(|i| json!({
+            "path": self.paths[i],
+            "exists": self.exists[i],
+            "size": self.size[i],
+            "mtime": self.mtime[i].unix_seconds(),
+            "mtime_nsec": self.mtime[i].nanoseconds(),
+            "hash": self.hash.as_ref().map(|x| x[i].clone()),
+        })).collect();
+
+        let decisions: Vec<JSONValue> = self.decisions.iter().zip(&self.blocked_by).map(|(decision, blocked_by)| {
+            let (action, from, to) = match *decision {
+                Decision::Unchanged => ("unchanged", None, None),
+                Decision::Touch { newest, oldest } => ("touch", Some(newest), Some(oldest)),
+                Decision::Copy { newest, oldest } => ("copy", Some(newest), Some(oldest)),
+            };
+
+            json!({
+                "decision": action,
+                "from": from,
+                "to": to,
+                "description": decision.describe(),
+                "blocked_by": blocked_by,
+            })
+        }).collect();
+
+        json!({
+            "files": files,
+            "hashed": self.hash.is_some(),
+            "decisions": decisions,
+        })
+    }
+
+    pub fn print(&self) {
+        println!("{}", self.paths.join(" vs "));
+        for i in (0..self.paths.len()).filter(|&i| !self.exists[i]) {
+            println!("\t#{} is missing", i + 1);
+        }
+        println!("\tmtime: {}", ::joined(&self.mtime, " --- "));
+        println!("\tsize: {}", ::joined(&self.size, " --- "));
+
+        match self.decisions[0] {
+            Decision::Unchanged => println!("\tmtimes match, contents not checked"),
+            Decision::Touch { newest, .. } | Decision::Copy { newest, .. } => println!("\t#{} is newer", newest + 1),
+        }
+
+        if let Some(ref hash) = self.hash {
+            let verdict = if hash.iter().all(|x| x.is_some() && *x == hash[0]) { "match" } else { "differ" };
+            let hash: Vec<&str> = hash.iter().map(|x| x.as_deref().unwrap_or("missing")).collect();
+            println!("\thash: {} ({})", hash.join(" --- "), verdict);
+        }
+
+        for (decision, blocked_by) in self.decisions.iter().zip(&self.blocked_by) {
+            println!("\tdecision: {}", decision.describe());
+
+            if let Some(ref reason) = *blocked_by {
+                println!("\tnot done: {}", reason);
+            }
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::Path;
+use std::process::Command;
+use users;
+
+/// Whether `path` has uncommitted changes in the git work tree it lives in. Files outside of a
+/// work tree, untracked files and machines without git never count.
+pub fn has_uncommitted_changes(path: &Path) -> bool {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(d), Some(n)) => (d, n),
+        _ => return false,
+    };
+
+    let mut git = Command::new("git");
+    git.arg("-C").arg(dir)
+        .args(["status", "--porcelain", "--untracked-files=no", "--"])
+        .arg(name);
+    // It runs whatever the repo's config says, like `core.fsmonitor`.
+    users::confine(&mut git);
+    let output = git.output();
+
+    match output {
+        Ok(out) => out.status.success() && !out.stdout.is_empty(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,108 @@
+//! The hash files are told apart by. SHA-1 is what staticsync always used, the others are picked
+//! with `hash` for speed, or for strength. Hashes other than SHA-1 are stored with the algorithm's
+//! name in front, so ones made before a switch are never taken for the same contents.
+
+use blake3;
+use crypto::{digest::Digest, sha1::Sha1, sha2::Sha256};
+use xxhash_rust::xxh64::Xxh64;
+
+/// Files at least this big are hashed on every core, when the algorithm can do that.
+pub const PARALLEL_THRESHOLD: u64 = 4 * 1024 * 1024;
+/// How much is read at a time then, it's split up between the threads.
+pub const PARALLEL_BUFFER: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Blake3,
+    Xxh64,
+}
+
+impl Algorithm {
+    pub const NAMES: &'static [&'static str] = &["sha1", "sha256", "blake3", "xxh64"];
+    const ALL: [Algorithm; 4] = [Algorithm::Sha1, Algorithm::Sha256, Algorithm::Blake3, Algorithm::Xxh64];
+
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        Algorithm::NAMES.iter().position(|&x| x == name).map(|i| Algorithm::ALL[i])
+    }
+
+    pub fn name(self) -> &'static str {
+        Algorithm::NAMES[self as usize]
+    }
+
+    /// A hasher whose hash comes out tagged with the algorithm's name.
+    pub fn hasher(self) -> Tagged {
+        let hasher: Box<dyn Hasher> = match self {
+            Algorithm::Sha1 => Box::new(Sha1::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            Algorithm::Xxh64 => Box::new(Xxh64::new(0)),
+        };
+        Tagged(self, hasher)
+    }
+
+    /// The hash of `data`.
+    pub fn digest(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+/// Something contents are fed through bit by bit to get their hash.
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    /// Whether `update_parallel` spreads the work over several threads.
+    fn parallel(&self) -> bool { false }
+    /// Same as `update`, using every core if it can. Only worth it for big chunks of data.
+    fn update_parallel(&mut self, data: &[u8]) { self.update(data) }
+    /// The hash of everything fed so far, in hex.
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for Sha1 {
+    fn update(&mut self, data: &[u8]) { self.input(data) }
+    fn finish(mut self: Box<Self>) -> String { self.result_str() }
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) { self.input(data) }
+    fn finish(mut self: Box<Self>) -> String { self.result_str() }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) { blake3::Hasher::update(self, data); }
+    fn parallel(&self) -> bool { true }
+    fn update_parallel(&mut self, data: &[u8]) { self.update_rayon(data); }
+    fn finish(self: Box<Self>) -> String { self.finalize().to_hex().to_string() }
+}
+
+impl Hasher for Xxh64 {
+    fn update(&mut self, data: &[u8]) { Xxh64::update(self, data) }
+    fn finish(self: Box<Self>) -> String { format!("{:016x}", self.digest()) }
+}
+
+pub struct Tagged(Algorithm, Box<dyn Hasher>);
+
+impl Tagged {
+    pub fn update(&mut self, data: &[u8]) {
+        self.1.update(data)
+    }
+
+    pub fn parallel(&self) -> bool {
+        self.1.parallel()
+    }
+
+    pub fn update_parallel(&mut self, data: &[u8]) {
+        self.1.update_parallel(data)
+    }
+
+    pub fn finish(self) -> String {
+        match self.0 {
+            Algorithm::Sha1 => self.1.finish(),
+            other => format!("{}-{}", other.name(), self.1.finish()),
+        }
+    }
+}
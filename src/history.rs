@@ -0,0 +1,136 @@
+//! Append-only log of every file staticsync replaced, for finding out long after the fact when
+//! and why it happened. Kept next to the sync state, one JSON record per line.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use backups;
+use seal::{self, Key};
+use serde_json::{self, Value as JSONValue};
+
+/// A file that was updated.
+pub struct Event {
+    /// Unix time it happened.
+    pub time: u64,
+    /// The pair the file belongs to.
+    pub paths: Vec<String>,
+    /// What it was replaced with, nothing when the changes of several files were merged into it.
+    pub from: Option<String>,
+    pub to: String,
+    /// Hash of the new contents, if they were hashed.
+    pub hash: Option<String>,
+    /// Hash of the contents it replaced, if there were any and they were hashed.
+    pub replaced_hash: Option<String>,
+    pub reason: String,
+}
+
+impl Event {
+    pub fn to_json(&self) -> JSONValue {
+        json!({
+            "time": self.time,
+            "paths": self.paths,
+            "from": self.from,
+            "to": self.to,
+            "hash": self.hash,
+            "replaced_hash": self.replaced_hash,
+            "reason": self.reason,
+        })
+    }
+
+    fn from_json(value: &JSONValue) -> Option<Event> {
+        Some(Event {
+            time: value.get("time")?.as_u64()?,
+            paths: value.get("paths")?.as_array()?.iter().filter_map(|x| x.as_str().map(String::from)).collect(),
+            from: value.get("from").and_then(|x| x.as_str()).map(String::from),
+            to: value.get("to")?.as_str()?.to_string(),
+            hash: value.get("hash").and_then(|x| x.as_str()).map(String::from),
+            replaced_hash: value.get("replaced_hash").and_then(|x| x.as_str()).map(String::from),
+            reason: value.get("reason").and_then(|x| x.as_str()).unwrap_or("").to_string(),
+        })
+    }
+
+    /// Whether the event is about `path`, or about something under it.
+    pub fn concerns(&self, path: &Path) -> bool {
+        self.paths.iter().any(|x| Path::new(x).starts_with(path))
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// The line `event` takes up in the log. With a `key`, it's encrypted on its own.
+fn line(key: Option<&Key>, event: &Event) -> io::Result<String> {
+    let data = serde_json::to_vec(&event.to_json())?;
+    Ok(match key {
+        Some(key) => hex(&seal::seal(key, &data)),
+        None => String::from_utf8(data).unwrap(),
+    })
+}
+
+/// Adds `event` to the log at `path`.
+pub fn append(path: &Path, key: Option<&Key>, event: &Event) -> io::Result<()> {
+    let line = line(key, event)?;
+
+    if let Some(dir) = path.parent() { fs::create_dir_all(dir)?; }
+    let fresh = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if fresh { backups::exclude(path); }
+    writeln!(file, "{}", line)
+}
+
+/// Everything in the log at `path`, oldest first. Lines that can't be read, like ones cut short by
+/// a crash, are left out, encrypted ones included: those don't decrypt anymore. Only when none of
+/// them do is it an error, the key must be the wrong one.
+pub fn read(path: &Path, key: Option<&Key>) -> io::Result<Vec<Event>> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut events = Vec::new();
+    let mut opened = false;
+    let mut failure = None;
+    for line in text.lines().filter(|x| !x.is_empty()) {
+        let data = match key {
+            Some(key) if !line.starts_with('{') => match unhex(line).map(|x| seal::open(key, &x)) {
+                Some(Ok(data)) => {
+                    opened = true;
+                    data
+                }
+                Some(Err(e)) => {
+                    failure = Some(e);
+                    continue;
+                }
+                None => continue,
+            },
+            _ => line.as_bytes().to_vec(),
+        };
+        if let Some(event) = serde_json::from_slice(&data).ok().as_ref().and_then(Event::from_json) {
+            events.push(event);
+        }
+    }
+    match failure {
+        Some(e) if !opened => Err(e),
+        _ => Ok(events),
+    }
+}
+
+/// Replaces the log at `path` with `events`, like when older ones are dropped.
+pub fn rewrite(path: &Path, key: Option<&Key>, events: &[Event]) -> io::Result<()> {
+    let mut text = String::new();
+    for event in events {
+        text.push_str(&line(key, event)?);
+        text.push('\n');
+    }
+
+    let tmp = path.with_extension("history.tmp");
+    fs::write(&tmp, text)?;
+    backups::exclude(&tmp);
+    fs::rename(&tmp, path)
+}
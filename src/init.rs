@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use dirs;
+use libc;
+use serde_json;
+
+/// Files people commonly want to keep in sync, relative to the home folder.
+const CANDIDATES: &[(&str, &str)] = &[
+    (".config/google-chrome/Default/Bookmarks", "Chrome bookmarks"),
+    (".config/chromium/Default/Bookmarks", "Chromium bookmarks"),
+    (".config/BraveSoftware/Brave-Browser/Default/Bookmarks", "Brave bookmarks"),
+    ("Library/Application Support/Google/Chrome/Default/Bookmarks", "Chrome bookmarks"),
+    ("AppData/Local/Google/Chrome/User Data/Default/Bookmarks", "Chrome bookmarks"),
+    ("notes.txt", "Notes"),
+    ("Documents/notes.txt", "Notes"),
+    ("Notes.md", "Notes"),
+];
+
+/// Whether there's someone on the other end to answer questions.
+pub fn interactive() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::isatty(1) == 1 }
+}
+
+/// Writes a config file syncing each pair in `pairs`.
+pub fn write_config(path: &Path, pairs: &[(String, String)]) -> io::Result<()> {
+    let files: Vec<[&str; 2]> = pairs.iter().map(|(a, b)| [a.as_str(), b.as_str()]).collect();
+    let mut file = File::create(path)?;
+    serde_json::to_writer_pretty(&mut file, &json!({ "files": files }))?;
+    writeln!(file)
+}
+
+/// Asks `question` and returns the answer, empty once there's nothing left to read.
+pub fn prompt(question: &str) -> io::Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+fn ask_path(question: &str) -> io::Result<Option<String>> {
+    loop {
+        let answer = prompt(question)?;
+        if answer.is_empty() { return Ok(None) }
+
+        let path = Path::new(&answer);
+        if !path.is_absolute() {
+            println!("The path must be absolute.");
+        } else if path.is_dir() {
+            println!("That's a directory.");
+        } else if !path.exists() {
+            println!("That file doesn't exist.");
+        } else {
+            return Ok(Some(answer));
+        }
+    }
+}
+
+/// Walks the user through creating a config at `path`. Returns whether one was written.
+pub fn wizard(path: &Path) -> io::Result<bool> {
+    let home = dirs::home_dir().unwrap_or_default();
+    let candidates: Vec<(PathBuf, &str)> = CANDIDATES.iter()
+        .map(|&(file, what)| (home.join(file), what))
+        .filter(|(file, _)| file.is_file())
+        .collect();
+    let mut pairs: Vec<(String, String)> = Vec::new();
+
+    println!("No config file found at \"{}\".", path.display());
+    let answer = prompt("Would you like to create one now? [Y/n] ")?.to_lowercase();
+    if !answer.is_empty() && !answer.starts_with('y') {
+        return Ok(false);
+    }
+
+    loop {
+        if !candidates.is_empty() {
+            println!("Some files you might want to sync:");
+            for (i, (file, what)) in candidates.iter().enumerate() {
+                println!("  {}) {} ({})", i + 1, file.display(), what);
+            }
+        }
+
+        let answer = prompt("File to sync (number or absolute path, empty to finish): ")?;
+        if answer.is_empty() { break }
+
+        let source = match answer.parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => candidates[n - 1].0.to_string_lossy().into_owned(),
+            _ => {
+                let path = Path::new(&answer);
+                if !path.is_absolute() || !path.is_file() {
+                    println!("That isn't an existing file with an absolute path.");
+                    continue;
+                }
+                answer
+            }
+        };
+
+        match ask_path("Where should it be kept in sync with? ")? {
+            Some(other) => {
+                if other == source {
+                    println!("Both paths are the same.");
+                    continue;
+                }
+                pairs.push((source, other))
+            }
+            None => continue,
+        }
+    }
+
+    if pairs.is_empty() {
+        return Ok(false);
+    }
+
+    write_config(path, &pairs)?;
+    println!("Wrote \"{}\".", path.display());
+    Ok(true)
+}
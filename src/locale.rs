@@ -0,0 +1,89 @@
+//! Times and numbers the way the user reads them, for output meant for people. Anything meant for
+//! other programs keeps plain numbers.
+
+/// Picks up the user's locale from the environment, for formatting times and numbers only.
+#[cfg(unix)]
+pub fn init() {
+    unsafe {
+        ::libc::setlocale(::libc::LC_TIME, b"\0".as_ptr() as *const ::libc::c_char);
+        ::libc::setlocale(::libc::LC_NUMERIC, b"\0".as_ptr() as *const ::libc::c_char);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn init() {}
+
+/// Year, month and day of the date `days` days after the epoch.
+pub fn civil_date(days: u64) -> (i64, u32, u32) {
+    // Howard Hinnant's days-to-civil, shifted so years start in March.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// `secs` since the epoch as an RFC 3339 time in UTC, like `2024-01-01T12:00:00Z`.
+pub fn rfc3339(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs / 86400);
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// `secs` since the epoch in the local time zone and the locale's format, or in UTC.
+pub fn time(secs: u64, utc: bool) -> String {
+    if utc { return rfc3339(secs) }
+    local_time(secs).unwrap_or_else(|| rfc3339(secs))
+}
+
+#[cfg(unix)]
+fn local_time(secs: u64) -> Option<String> {
+    let secs = secs as ::libc::time_t;
+    let mut tm: ::libc::tm = unsafe { ::std::mem::zeroed() };
+    if unsafe { ::libc::localtime_r(&secs, &mut tm) }.is_null() { return None }
+
+    let mut buf = [0u8; 128];
+    let len = unsafe {
+        ::libc::strftime(buf.as_mut_ptr() as *mut ::libc::c_char, buf.len(), b"%c %Z\0".as_ptr() as *const ::libc::c_char, &tm)
+    };
+    if len == 0 { None } else { Some(String::from_utf8_lossy(&buf[..len]).into_owned()) }
+}
+
+/// Without a portable way to get the local time zone, times are shown in UTC.
+#[cfg(not(unix))]
+fn local_time(_secs: u64) -> Option<String> {
+    None
+}
+
+/// `n` with the locale's thousands separator, if it has one.
+pub fn number(n: u64) -> String {
+    let digits = n.to_string();
+    let separator = thousands_separator();
+    if separator.is_empty() { return digits }
+
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) { out.push_str(&separator); }
+        out.push(digit);
+    }
+    out
+}
+
+#[cfg(unix)]
+fn thousands_separator() -> String {
+    unsafe {
+        let conv = ::libc::localeconv();
+        if conv.is_null() || (*conv).thousands_sep.is_null() { return String::new() }
+        ::std::ffi::CStr::from_ptr((*conv).thousands_sep).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(not(unix))]
+fn thousands_separator() -> String {
+    String::new()
+}
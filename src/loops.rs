@@ -0,0 +1,100 @@
+//! Finds entries that together would keep copying files around forever, like a directory that ends
+//! up synced with one inside itself by way of another entry, so the config can be refused.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use config::{Entry, Kind};
+
+/// Paths followed from a directory before giving up, loops show up long before that.
+const SEARCH_LIMIT: usize = 1000;
+
+/// What's wrong, if some of `entries` loop.
+pub fn find(entries: &[Entry]) -> Option<String> {
+    nested(entries).or_else(|| forced_twice(entries)).or_else(|| circle(entries))
+}
+
+/// A directory that ends up synced with one inside itself, or around itself, through any number of
+/// entries: whatever is in it gets copied one level deeper on every pass.
+fn nested(entries: &[Entry]) -> Option<String> {
+    let dirs: Vec<Vec<&Path>> = entries.iter().filter(|x| x.kind == Kind::Directories && !x.pending)
+        .map(|x| x.paths.iter().map(Path::new).collect()).collect();
+
+    for &start in dirs.iter().flatten() {
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        let mut queue = vec![start.to_path_buf()];
+
+        while let Some(path) = queue.pop() {
+            if path != start && (path.starts_with(start) || start.starts_with(&path)) {
+                return Some(format!("\"{}\" ends up synced with \"{}\"", start.display(), path.display()));
+            }
+            if seen.len() > SEARCH_LIMIT { break }
+            if !seen.insert(path.clone()) { continue }
+
+            // Whatever is under one directory of an entry is the same under the others.
+            for roots in &dirs {
+                for &root in roots.iter().filter(|x| path.starts_with(x)) {
+                    let relative = path.strip_prefix(root).unwrap();
+                    queue.extend(roots.iter().filter(|&&x| x != root).map(|x| x.join(relative)));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// A path that two entries each make like a different master, it would flip between them on every
+/// pass.
+fn forced_twice(entries: &[Entry]) -> Option<String> {
+    let mut masters: HashMap<&Path, &Path> = HashMap::new();
+
+    for entry in entries {
+        let master = match entry.master {
+            Some(m) => Path::new(&entry.paths[m]),
+            None => continue,
+        };
+        for path in entry.paths.iter().map(Path::new).filter(|&x| x != master) {
+            match masters.insert(path, master) {
+                Some(other) if other != master => return Some(format!("\"{}\" is made like both \"{}\" and \"{}\"",
+                    path.display(), other.display(), master.display())),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Paths that sync one way in a circle, each made like the one before it. With masters, a change
+/// anywhere goes around for good, and otherwise they end up syncing both ways after all.
+fn circle(entries: &[Entry]) -> Option<String> {
+    let mut order: Vec<&Path> = Vec::new();
+    let mut edges: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for entry in entries {
+        let from = match entry.source() {
+            Some(s) => Path::new(&entry.paths[s]),
+            None => continue,
+        };
+        if !edges.contains_key(from) { order.push(from); }
+        edges.entry(from).or_default().extend(entry.paths.iter().map(Path::new).filter(|&x| x != from));
+    }
+
+    for start in order {
+        // Depth first, keeping the way there to show it.
+        let mut stack: Vec<Vec<&Path>> = vec![vec![start]];
+        let mut seen: HashSet<&Path> = HashSet::new();
+
+        while let Some(way) = stack.pop() {
+            for &next in edges.get(way.last().unwrap()).into_iter().flatten() {
+                if next == start {
+                    let shown: Vec<String> = way.iter().chain(Some(&start)).map(|x| format!("\"{}\"", x.display())).collect();
+                    return Some(format!("files go around in a circle: {}", shown.join(" -> ")));
+                }
+                if seen.insert(next) {
+                    let mut longer = way.clone();
+                    longer.push(next);
+                    stack.push(longer);
+                }
+            }
+        }
+    }
+    None
+}
@@ -1,33 +1,87 @@
+extern crate blake3;
 extern crate crypto;
 extern crate dirs;
 extern crate filetime;
 extern crate getopts;
+extern crate glob;
+extern crate ignore;
+extern crate libc;
+extern crate rhai;
+#[macro_use]
 extern crate serde_json;
+extern crate xxhash_rust;
+extern crate zstd;
 
-use std::{env, io::Error, io::Read, process::exit, thread::sleep, time::Duration};
-use std::fs::{File, Metadata, copy, metadata};
+mod artifacts;
+mod backups;
+mod config;
+mod copy;
+mod cow;
+mod decision;
+mod delta;
+mod exclude;
+mod explain;
+mod git;
+mod hashing;
+mod history;
+mod init;
+mod locale;
+mod loops;
+mod merge;
+mod policy;
+mod redact;
+mod requirements;
+mod sandbox;
+mod schedule;
+mod seal;
+mod snapshot;
+mod state;
+mod timing;
+mod users;
+mod vss;
+mod walk;
+mod warnings;
+
+use std::collections::{HashMap, HashSet};
+use std::{env, fmt, io::Error, io::ErrorKind, io::Read, process::exit, thread::sleep, time::Duration};
+use std::fs::{self, File, Metadata, metadata};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use config::{Compare, Config, Delete, Kind, OnConflict};
+use decision::{Decision, decide, decide_by_hash, same_mtime};
+use snapshot::Snapshot;
 use crypto::{digest::Digest, sha1::Sha1};
 use getopts::Options;
 use filetime::{FileTime, set_file_times};
+use hashing::Algorithm;
+use policy::Verdict;
+use schedule::WeekTime;
 use serde_json::{Value as JSONValue};
+use state::{PairState, PassResult, State, Undo};
+use users::User;
+use warnings::Warning;
 
-const FILES_THE_SAME: &'static str = "Files are the same! Not updating.";
+const FILES_THE_SAME: &str = "Files are the same! Not updating.";
 const BUFFER_SIZE: usize = 8096; // 8 KB
 const SLEEP_TIME: u64 = 10;
+/// How often trigger files are checked between passes.
+const TRIGGER_POLL: Duration = Duration::from_secs(1);
+/// How often the daemon drops records of pairs that are gone from the sync state.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 enum SetupError {
     MalformedCLI(String),
     ConfigLoadError(String),
+    StateLoadError(String),
 }
 
-impl SetupError {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use SetupError::*;
         match self {
-            MalformedCLI(s) => s.clone(),
-            ConfigLoadError(s) => s.clone(),
+            MalformedCLI(s) => write!(f, "{}", s),
+            ConfigLoadError(s) => write!(f, "{}", s),
+            StateLoadError(s) => write!(f, "Couldn't load sync state: {}", s),
         }
     }
 }
@@ -44,36 +98,293 @@ impl From<serde_json::Error> for SetupError {
     }
 }
 
+enum Command {
+    Sync,
+    Diff,
+    List,
+    Init,
+    Simulate,
+    Snapshot,
+    StateRepair,
+    StateCompact,
+    Undo,
+    /// Only the updates to files at or under the path, if one is given.
+    History(Option<String>),
+    Export,
+    Artifacts,
+    Duplicates,
+    Du,
+    Explain(String),
+    Compare(String, String),
+    /// Settles the conflict with the id the way `on_conflict` would, or lists them all.
+    Conflicts(Option<(String, OnConflict)>),
+    Doctor,
+    /// Whether to list what was skipped instead of summing up.
+    Status(bool),
+}
+
+enum Output {
+    Text,
+    Json,
+}
+
+struct Settings {
+    command: Command,
+    output: Output,
+    snapshot: Option<PathBuf>,
+    hashes: bool,
+    /// Explain decisions for every pair, or only those containing the given path.
+    explain: Option<Option<String>>,
+    verbose: bool,
+    once: bool,
+    buffer_size: usize,
+    direct_io: Option<u64>,
+    /// `du` removes leftovers older than this.
+    older_than: Option<Duration>,
+    /// `du` removes the oldest leftovers until they fit in this many bytes.
+    budget: Option<u64>,
+    /// Show times in UTC rather than in the local time zone.
+    utc: bool,
+    /// Names of the only entries to sync, all of them if empty.
+    only: Vec<String>,
+    /// Only entries with one of these tags are synced, unless it's empty.
+    tags: Vec<String>,
+    /// Passes only look, warning about pairs that differ instead of syncing them.
+    observe: bool,
+    /// Conflicts are settled by `on_conflict` even when there's someone to ask.
+    non_interactive: bool,
+}
+
+impl Settings {
+    /// Whether `entry` is one of those picked with `--only` and `--tags`, if any were.
+    fn selects(&self, entry: &config::Entry) -> bool {
+        (self.only.is_empty() || entry.name.as_ref().is_some_and(|x| self.only.contains(x)))
+            && (self.tags.is_empty() || entry.tags.iter().any(|x| self.tags.contains(x)))
+    }
+}
+
+/// A config file along with its own state and schedule.
+struct Profile {
+    config: Config,
+    state: State,
+    /// Whose privileges the syncs run with in system mode.
+    user: Option<User>,
+    sleep_time: Duration,
+    next_run: Instant,
+    /// Last seen mtime of each entry's trigger file, if it has one and it exists.
+    triggers: Vec<Option<FileTime>>,
+    next_verify: Option<Instant>,
+    next_compact: Instant,
+    /// Entries skipped last time because their paths weren't there, or weren't what they were.
+    unavailable: HashSet<usize>,
+    /// Whether the stop file was there last time.
+    stopped: bool,
+    /// Pairs found to differ while observing, already warned about.
+    diverged: HashSet<Vec<String>>,
+}
+
+impl Profile {
+    /// Entries whose trigger file was touched since the last call, along with the rest of their
+    /// atomic groups.
+    fn fired_triggers(&mut self) -> Vec<usize> {
+        let mut fired: Vec<usize> = Vec::new();
+
+        for (i, entry) in self.config.entries.iter().enumerate() {
+            let mtime = entry.trigger_file.as_ref()
+                .and_then(|x| metadata(x).ok())
+                .map(|x| FileTime::from_last_modification_time(&x));
+            if mtime.is_some() && mtime != self.triggers[i] { fired.push(i); }
+            self.triggers[i] = mtime;
+        }
+
+        let groups: Vec<&str> = fired.iter().filter_map(|&i| self.config.entries[i].group.as_deref()).collect();
+        for (i, entry) in self.config.entries.iter().enumerate() {
+            if entry.group.as_deref().is_some_and(|x| groups.contains(&x)) && !fired.contains(&i) { fired.push(i); }
+        }
+
+        fired
+    }
+
+    /// Whether the stop file is there, saying so whenever that changes.
+    fn stop_requested(&mut self) -> bool {
+        let stop_file = match self.config.stop_file {
+            Some(ref f) => f,
+            None => return false,
+        };
+
+        let stopped = stop_file.exists();
+        if stopped && !self.stopped {
+            println!("Stopped: \"{}\" is there, remove it to carry on", stop_file.display());
+        } else if !stopped && self.stopped {
+            println!("\"{}\" is gone, carrying on", stop_file.display());
+        }
+        self.stopped = stopped;
+        stopped
+    }
+}
+
 fn error(string: &str) -> ! {
     println!("\x1b[1m\x1b[91mERROR: {}\x1b[0m", string);
     exit(1);
 }
 
+fn warning(kind: Warning, string: &str) {
+    if warnings::suppressed(kind) { return }
+    println!("\x1b[1m\x1b[93mWARNING [{}]: {}\x1b[0m", kind.code(), string);
+}
+
+/// A warning about `entry`, unless it's one the entry doesn't want.
+fn entry_warning(entry: &config::Entry, kind: Warning, string: &str) {
+    if !entry.suppress_warnings.contains(&kind) { warning(kind, string); }
+}
+
 fn usage() { 
-    println!(r#"staticsync [OPTIONS]
+    println!(r#"staticsync [OPTIONS] [COMMAND]
+
+COMMANDS:
+sync                Keep the configured files in sync (default)
+diff                Show which pairs differ and how, exits with 1 if any do or can't be checked
+list                List the configured entries and how their last check went
+status [--skipped]  Sum up how the last checks went, or list what they skipped and why
+init [A B]...       Create a config file syncing each pair of paths given, asks if there are none
+simulate            Show what a pass would do given the metadata recorded in --snapshot
+snapshot            Print the current metadata of every configured file as a snapshot
+state repair        Fix inconsistent sync state, or rebuild it from a fresh scan if it's unreadable
+state compact       Drop the records of pairs that are gone or no longer synced
+undo                Put back the files the last pass that changed anything replaced or touched
+history [PATH]      List when files were updated and why, or only the ones at or under PATH
+export              Dump the sync state of every pair as CSV
+explain PATH        Show how the pair containing PATH would be handled right now
+compare PATH OTHER  Check which files of the pair containing PATH match OTHER and when they did, exits with 1 if none do
+conflicts list      List the pairs held because more than one of their files changed
+conflicts resolve ID --keep left|right|both
+                    Make the other files of the conflict ID like the left one, the right one, or the newest while keeping them
+du                  Show how much space what staticsync keeps around takes up, see --older-than and --budget
+duplicates          Show files with the same contents in different entries, exits with 1 if there are any
+artifacts           List the patterns of the temp files staticsync leaves behind, which are never synced
+doctor              Check how well the daemon can be sandboxed here, and whether it keeps network access
 
 OPTIONS:
 -c, --config CONFIG Path to a configuration file. Will use .staticsync.json in your home folder if unspecified.
+                    Can be given more than once to run several configs together.
+-p, --profiles DIR  Also load every .json config in DIR
+    --system        Run every user's config from /etc/staticsync/users.d/USER.json with their privileges (root only)
 -d, --delay SECONDS Delay time between each check
 -s, --size SIZE     Hashing buffer size, in bytes (default: 8 KB, 8096)
 -v, --verbose       Show more information when synching
--o, --once          Only run sync once"#);
+    --explain[=PATH]
+                    Show the reasoning behind every decision, or only for pairs containing PATH
+-o, --once          Only run sync once
+-D, --direct-io SIZE
+                    Bypass the page cache when copying files of at least SIZE bytes
+    --output FORMAT Output format for listings, text or json (default: text)
+    --snapshot FILE Snapshot to simulate a pass against
+    --hashes        Include file hashes in snapshots
+    --format FORMAT Export format, only csv for now (default: csv)
+    --skipped       Make status list what was skipped
+    --older-than DAYS
+                    Make du delete interrupted copies older than DAYS
+    --budget SIZE   Make du delete the oldest interrupted copies until they take up at most SIZE bytes
+    --keep SIDE     Which file conflicts resolve keeps: left, right or both
+    --utc           Show times in UTC instead of the local time zone
+    --only NAME     Only sync or diff the entry named NAME, can be given more than once
+    --tags TAGS     Only sync or diff the entries with one of these comma-separated tags
+    --observe       Never write anything, only warn about pairs that differ
+    --non-interactive
+                    Let on_conflict settle conflicts instead of asking, even in a terminal
+    --hash ALGO     Hash files with sha1, sha256, blake3 or xxh64, overriding the configs (default: sha1)
+    --profile FILE  Write where the time of passes went to FILE, as folded stacks for flamegraphs"#);
+}
+
+fn run_init(config_file: &Path, args: &[String]) -> Result<(), SetupError> {
+    if config_file.exists() {
+        return Err(SetupError::ConfigLoadError(format!("\"{}\" already exists", config_file.display())));
+    }
+
+    if args.is_empty() && init::interactive() {
+        init::wizard(config_file)?;
+        return Ok(());
+    }
+
+    if !args.len().is_multiple_of(2) {
+        return Err(SetupError::MalformedCLI("init takes pairs of paths".to_string()));
+    }
+
+    let pairs: Vec<(String, String)> = args.chunks(2).map(|x| (x[0].clone(), x[1].clone())).collect();
+    init::write_config(config_file, &pairs)?;
+    println!("Wrote \"{}\".", config_file.display());
+    Ok(())
+}
+
+fn state_path(config_file: &Path, default_config: bool) -> Result<PathBuf, SetupError> {
+    // Every config gets its own state so the same pair in two configs doesn't get mixed up.
+    let namespace = if default_config {
+        None
+    } else {
+        let canonical = fs::canonicalize(config_file)?;
+        let mut hasher = Sha1::new();
+        hasher.input_str(&canonical.to_string_lossy());
+        Some(hasher.result_str()[..12].to_string())
+    };
+
+    match State::default_path(namespace.as_deref()) {
+        Some(p) => Ok(p),
+        None => Err(SetupError::StateLoadError("No data directory available".to_string()))
+    }
+}
+
+/// Finds the per-user configs for system mode.
+fn system_configs() -> Result<Vec<(PathBuf, Option<User>)>, SetupError> {
+    if !users::running_as_root() {
+        return Err(SetupError::MalformedCLI("System mode needs to run as root".to_string()));
+    }
+
+    let mut configs: Vec<(PathBuf, Option<User>)> = Vec::new();
+    let mut found: Vec<PathBuf> = fs::read_dir(users::USERS_DIR)?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| x.is_file() && x.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    found.sort();
+
+    for path in found {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        match users::lookup(&name) {
+            Some(user) => configs.push((path, Some(user))),
+            None => return Err(SetupError::ConfigLoadError(format!("Unknown user \"{}\" for \"{}\"", name, path.display()))),
+        }
+    }
+
+    Ok(configs)
 }
 
-fn setup() -> Result<(bool, bool, usize, JSONValue, Duration), SetupError> {
+fn setup() -> Result<(Settings, Vec<Profile>), SetupError> {
     let args: Vec<String> = env::args().collect();
-    let config_file: String;
-    let sleep_time: Duration;
-    let buffer_size: usize;
-    let verbose: bool;
-    let once: bool;
 
     let mut opts = Options::new();
-    opts.optopt("c", "config", "", "");
+    opts.optmulti("c", "config", "", "");
+    opts.optopt("p", "profiles", "", "");
+    opts.optflag("", "system", "");
     opts.optopt("d", "delay", "", "");
     opts.optopt("s", "size", "", "");
     opts.optflag("v", "verbose", "");
+    opts.optflagopt("", "explain", "", "");
     opts.optflag("o", "once", "");
+    opts.optopt("D", "direct-io", "", "");
+    opts.optopt("", "output", "", "");
+    opts.optopt("", "snapshot", "", "");
+    opts.optflag("", "hashes", "");
+    opts.optopt("", "format", "", "");
+    opts.optopt("", "older-than", "", "");
+    opts.optopt("", "budget", "", "");
+    opts.optflag("", "skipped", "");
+    opts.optflag("", "utc", "");
+    opts.optmulti("", "only", "", "");
+    opts.optmulti("", "tags", "", "");
+    opts.optflag("", "observe", "");
+    opts.optflag("", "non-interactive", "");
+    opts.optopt("", "hash", "", "");
+    opts.optopt("", "profile", "", "");
+    opts.optopt("", "keep", "", "");
     opts.optflag("h", "help", "");
 
     let matches = match opts.parse(&args[1..]) {
@@ -86,10 +397,77 @@ fn setup() -> Result<(bool, bool, usize, JSONValue, Duration), SetupError> {
         exit(0);
     }
 
-    verbose = matches.opt_present("verbose");
-    once = matches.opt_present("once");
+    let command = match matches.free.first().map(|x| x.as_str()) {
+        None | Some("sync") => Command::Sync,
+        Some("diff") => Command::Diff,
+        Some("list") => Command::List,
+        Some("status") => Command::Status(matches.opt_present("skipped")),
+        Some("init") => Command::Init,
+        Some("simulate") => Command::Simulate,
+        Some("snapshot") => Command::Snapshot,
+        Some("export") => Command::Export,
+        Some("undo") => Command::Undo,
+        Some("history") => Command::History(matches.free.get(1).cloned()),
+        Some("artifacts") => Command::Artifacts,
+        Some("duplicates") => Command::Duplicates,
+        Some("du") => Command::Du,
+        Some("doctor") => Command::Doctor,
+        Some("explain") => match matches.free.get(1) {
+            Some(path) => Command::Explain(path.clone()),
+            None => return Err(SetupError::MalformedCLI("Usage: staticsync explain PATH".to_string()))
+        },
+        Some("compare") => match (matches.free.get(1), matches.free.get(2)) {
+            (Some(path), Some(other)) => Command::Compare(path.clone(), other.clone()),
+            _ => return Err(SetupError::MalformedCLI("Usage: staticsync compare PATH OTHER".to_string()))
+        },
+        Some("conflicts") => match (matches.free.get(1).map(|x| x.as_str()), matches.free.get(2), matches.opt_str("keep").as_deref()) {
+            (None | Some("list"), None, None) => Command::Conflicts(None),
+            (Some("resolve"), Some(id), Some("left")) => Command::Conflicts(Some((id.clone(), OnConflict::Left))),
+            (Some("resolve"), Some(id), Some("right")) => Command::Conflicts(Some((id.clone(), OnConflict::Right))),
+            (Some("resolve"), Some(id), Some("both")) => Command::Conflicts(Some((id.clone(), OnConflict::KeepBoth))),
+            _ => return Err(SetupError::MalformedCLI("Usage: staticsync conflicts list|resolve ID --keep left|right|both".to_string()))
+        },
+        Some("state") => match matches.free.get(1).map(|x| x.as_str()) {
+            Some("repair") => Command::StateRepair,
+            Some("compact") => Command::StateCompact,
+            _ => return Err(SetupError::MalformedCLI("Usage: staticsync state repair|compact".to_string()))
+        },
+        Some(s) => return Err(SetupError::MalformedCLI(format!("Unknown command: {}", s)))
+    };
+
+    let output = match matches.opt_str("output").as_deref() {
+        None | Some("text") => Output::Text,
+        Some("json") => Output::Json,
+        Some(s) => return Err(SetupError::MalformedCLI(format!("Unknown output format: {}", s)))
+    };
+
+    let hash = match matches.opt_str("hash") {
+        Some(s) => match hashing::Algorithm::parse(&s) {
+            Some(a) => Some(a),
+            None => return Err(SetupError::MalformedCLI(format!("Unknown hash: {}, use one of {}", s, hashing::Algorithm::NAMES.join(", "))))
+        },
+        None => None,
+    };
+
+    if let Some(path) = matches.opt_str("profile") { timing::enable(PathBuf::from(path)); }
+
+    let snapshot = matches.opt_str("snapshot").map(PathBuf::from);
+    if let (Command::Simulate, None) = (&command, &snapshot) {
+        return Err(SetupError::MalformedCLI("simulate needs a --snapshot".to_string()));
+    }
+
+    match matches.opt_str("format").as_deref() {
+        None | Some("csv") => {}
+        Some("parquet") => return Err(SetupError::MalformedCLI("Parquet export isn't supported, use csv".to_string())),
+        Some(s) => return Err(SetupError::MalformedCLI(format!("Unknown export format: {}", s)))
+    }
+
+    let explain = if matches.opt_present("explain") { Some(matches.opt_str("explain")) } else { None };
+    let hashes = matches.opt_present("hashes");
+    let verbose = matches.opt_present("verbose");
+    let once = matches.opt_present("once");
 
-    buffer_size = match matches.opt_str("size") {
+    let buffer_size = match matches.opt_str("size") {
         Some(s) => {
             if let Ok(n) = s.parse::<usize>() {
                 n
@@ -101,20 +479,67 @@ fn setup() -> Result<(bool, bool, usize, JSONValue, Duration), SetupError> {
         None => BUFFER_SIZE
     };
 
-    config_file = match matches.opt_str("config") {
-        Some(s) => s,
-        None => {
-            let mut buf: PathBuf = dirs::home_dir().unwrap();
-            buf.push(".staticsync.json");
-            if !buf.as_path().is_file() {
-                return Err(SetupError::ConfigLoadError("Missing config file".to_string()))
-            }
+    let direct_io = match matches.opt_str("direct-io") {
+        Some(s) => match s.parse::<u64>() {
+            Ok(n) => Some(n),
+            Err(_) => return Err(SetupError::MalformedCLI("Invalid direct IO threshold".to_string()))
+        },
+        None => None
+    };
 
-            buf.to_str().unwrap().to_string()
-        }
+    let older_than = match matches.opt_str("older-than") {
+        Some(s) => match s.parse::<u64>() {
+            Ok(n) => Some(Duration::from_secs(n * 24 * 60 * 60)),
+            Err(_) => return Err(SetupError::MalformedCLI("Invalid number of days".to_string()))
+        },
+        None => None
     };
 
-    sleep_time = Duration::from_secs(match matches.opt_str("delay") {
+    let budget = match matches.opt_str("budget") {
+        Some(s) => match s.parse::<u64>() {
+            Ok(n) => Some(n),
+            Err(_) => return Err(SetupError::MalformedCLI("Invalid size budget".to_string()))
+        },
+        None => None
+    };
+
+    let mut config_files: Vec<(PathBuf, Option<User>)> = matches.opt_strs("config").into_iter()
+        .map(|x| (PathBuf::from(x), None)).collect();
+
+    if let Some(dir) = matches.opt_str("profiles") {
+        let mut found: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|x| x.ok().map(|x| x.path()))
+            .filter(|x| x.is_file() && x.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        found.sort();
+        config_files.extend(found.into_iter().map(|x| (x, None)));
+    }
+
+    if matches.opt_present("system") {
+        config_files.extend(system_configs()?);
+    }
+
+    let default_config = config_files.is_empty();
+    if default_config {
+        let mut buf: PathBuf = dirs::home_dir().unwrap();
+        buf.push(".staticsync.json");
+        config_files.push((buf, None));
+    }
+
+    if let Command::Init = command {
+        run_init(&config_files[0].0, &matches.free[1..])?;
+        exit(0);
+    }
+
+    if let Command::Artifacts = command {
+        match output {
+            Output::Json => println!("{}", json!(artifacts::patterns())),
+            Output::Text => artifacts::patterns().iter().for_each(|x| println!("{}", x)),
+        }
+        exit(0);
+    }
+
+    let sleep_time = Duration::from_secs(match matches.opt_str("delay") {
         Some(s) => {
             let secs: Option<u64> = s.parse::<u64>().ok();
             match secs {
@@ -125,118 +550,2558 @@ fn setup() -> Result<(bool, bool, usize, JSONValue, Duration), SetupError> {
         None => SLEEP_TIME
     });
 
-    println!("Loading config \"{}\"...", config_file);
-    let file = File::open(config_file)?;
+    let mut profiles: Vec<Profile> = Vec::new();
 
-    let value: JSONValue = serde_json::from_reader(file)?;
-    let same_error = |x: &str| { SetupError::ConfigLoadError(format!("Duplicated path: {}", x)) };
-    let dir_error = |x: &str| { SetupError::ConfigLoadError(format!("Path \"{}\" is a directory!", x)) };
-    let abs_error = |x: &str| { SetupError::ConfigLoadError(format!("Path must be absolute: {}", x)) };
-    let exs_error = |x: &str| { SetupError::ConfigLoadError(format!("File \"{}\" does not exist!", x)) };
+    for (config_file, user) in config_files {
+        if !config_file.is_file() {
+            let created = init::interactive() && init::wizard(&config_file)?;
+            if !created {
+                return Err(SetupError::ConfigLoadError(format!("Missing config file \"{}\"", config_file.display())))
+            }
+        }
 
-    {
-        // Validate paths
-        let files: &Vec<JSONValue> = value.get("files").unwrap().as_array().unwrap();
-        for entry in files {
-            let buf: Vec<PathBuf> = entry.as_array().unwrap().iter().take(2).map(|x| PathBuf::from(x.as_str().unwrap())).collect();
-            let path: Vec<&Path> = buf.iter().map(|x| x.as_path()).collect();
+        // Keep stdout clean for anything meant to be read by other programs.
+        let machine_readable = matches!(output, Output::Json) || matches!(command, Command::Snapshot | Command::Export);
+        if !machine_readable { println!("Loading config \"{}\"...", config_file.display()); }
+        // Validate with the user's eyes, and read their key and state, kept in their own home,
+        // with them too: those are paths they control.
+        let _guard = match user {
+            Some(ref user) => Some(users::impersonate(user)?),
+            None => None,
+        };
+        let state_file = match user {
+            Some(ref user) => user.home.join(".local/share/staticsync/state-system.json"),
+            None => state_path(&config_file, default_config)?,
+        };
+        let mut config = config::load(&config_file)?;
+        // Given on the command line, it's for every config.
+        if let Some(algorithm) = hash { config.hash_algorithm = algorithm; }
+        if let Some(ref salt) = config.redact_paths { redact::enable(salt); }
+        if config.exclude_from_backups { backups::enable(); }
+        warnings::suppress(&config.suppress_warnings);
+        let key = match config.state_key {
+            Some(ref path) => Some(seal::Key::load(path).map_err(|e| {
+                SetupError::StateLoadError(format!("couldn't read the key in \"{}\": {}", path.display(), e))
+            })?),
+            None => None,
+        };
+        let mut state = match State::load(state_file.clone(), key.clone()) {
+            Ok(s) => s,
+            Err(e) => match command {
+                Command::StateRepair => {
+                    warning(Warning::State, &format!("Couldn't load \"{}\" ({}), rebuilding it", state_file.display(), e));
+                    State::empty(state_file, key)
+                }
+                _ => return Err(SetupError::StateLoadError(format!("{} (try \"staticsync state repair\")", e))),
+            }
+        };
 
-            // Check if paths are absolute
-            if !path[0].is_absolute() { return Err(abs_error(path[0].to_str().unwrap())); }
-            if !path[1].is_absolute() { return Err(abs_error(path[1].to_str().unwrap())); }
+        if !machine_readable { println!("Config hash: {}", config.hash); }
+        if let Some(ref old) = state.config_hash {
+            if *old != config.hash && !machine_readable {
+                warning(Warning::ConfigChanged, &format!("\"{}\" changed since it was last used (was {})", config_file.display(), old));
+            }
+        }
+        state.set_config_hash(&config.hash);
 
-            // Check if paths are directories
-            if path[0].is_dir() { return Err(dir_error(path[0].to_str().unwrap())); }
-            if path[1].is_dir() { return Err(dir_error(path[1].to_str().unwrap())); }
+        if !machine_readable {
+            for problem in state.check(|x| config.covers(x)) {
+                warning(Warning::State, &format!("Sync state: {} (run \"staticsync state repair\" to fix)", problem));
+            }
+        }
 
-            // Check if paths are duplicated
-            if path[0] == path[1] { return Err(same_error(path[0].to_str().unwrap())); }
+        let mut profile = Profile {
+            sleep_time: config.delay.unwrap_or(sleep_time),
+            next_run: Instant::now(),
+            triggers: vec![None; config.entries.len()],
+            next_verify: config.verify_interval.map(|x| Instant::now() + x),
+            next_compact: Instant::now() + COMPACT_INTERVAL,
+            config,
+            state,
+            user,
+            unavailable: HashSet::new(),
+            stopped: false,
+            diverged: HashSet::new(),
+        };
+        profile.fired_triggers();
+        profiles.push(profile);
+    }
 
-            // Check if files exist
-            // TODO: Check for both files not existing instead (sync)
-            if !path[0].exists() { return Err(exs_error(path[0].to_str().unwrap())); }
-            if !path[1].exists() { return Err(exs_error(path[1].to_str().unwrap())); }
+    let only = matches.opt_strs("only");
+    for name in &only {
+        if !profiles.iter().any(|x| x.config.entries.iter().any(|y| y.name.as_ref() == Some(name))) {
+            return Err(SetupError::MalformedCLI(format!("No entry is named \"{}\"", name)));
         }
     }
 
-    Ok((verbose, once, buffer_size, value, sleep_time))
+    let tags: Vec<String> = matches.opt_strs("tags").iter()
+        .flat_map(|x| x.split(',').map(|y| y.trim().to_string()).filter(|y| !y.is_empty()).collect::<Vec<_>>())
+        .collect();
+
+    let settings = Settings {
+        command, output, snapshot, hashes, explain, verbose, once, buffer_size, direct_io, older_than, budget, only, tags,
+        utc: matches.opt_present("utc"),
+        observe: matches.opt_present("observe"),
+        non_interactive: matches.opt_present("non-interactive"),
+    };
+    Ok((settings, profiles))
 }
 
-fn calculate_hash(buffer_size: usize, path: &str) -> Result<String, Error> {
-    let mut file = File::open(path)?;
-    let mut buf: Vec<u8> = Vec::with_capacity(buffer_size);
-    unsafe { buf.set_len(buffer_size); }
-    let mut hasher = Sha1::new();
+/// Every set of files `config` keeps in sync, along with the entry it comes from. Entries whose
+/// directories can't be listed are left out.
+fn pairs_of(config: &Config) -> Vec<(&config::Entry, Vec<String>)> {
+    let mut pairs = Vec::new();
 
-    loop {
-        let n = file.read(&mut buf)?;
-        hasher.input(&buf[..n]);
-        if n == 0 || n < buf.len() { break }
+    for entry in &config.entries {
+        match entry.pairs() {
+            Ok(p) => pairs.extend(p.into_iter().map(|x| (entry, x))),
+            Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e)),
+        }
     }
-    
-    Ok(hasher.result_str())
+
+    pairs
 }
 
-fn sync(verbose: bool, buffer_size: usize, config: &JSONValue) {
-    use std::cmp::Ordering;
+/// Finds the pair `path` belongs to.
+fn find_pair<'a>(profiles: &'a [Profile], path: &str) -> Option<(&'a Profile, &'a config::Entry, Vec<String>)> {
+    profiles.iter()
+        .flat_map(|p| pairs_of(&p.config).into_iter().map(move |(e, x)| (p, e, x)))
+        .find(|(_, _, x)| x.iter().any(|y| y == path))
+}
 
-    println!("Checking...");
-    let files = config.get("files").unwrap().as_array().unwrap();
+/// Checks which files of a pair have the same contents as `other`, like an old copy on a backup
+/// drive, and when the pair's history last saw those contents. Returns whether any file has them
+/// now.
+fn compare(settings: &Settings, algorithm: Algorithm, history: &[history::Event], paths: &[String], other: &str) -> Result<bool, Error> {
+    let other_meta = metadata(other)?;
+    // Needed either way, to look it up in the history.
+    let other_hash = calculate_hash(algorithm, settings.buffer_size, other)?;
+    let mut files: Vec<JSONValue> = Vec::new();
+    let mut matching: Vec<&str> = Vec::new();
 
-    for entry in files {
-        let path: Vec<&str> = entry.as_array().unwrap()
-            .iter().take(2).map(|x| x.as_str().unwrap()).collect();
-        // TODO: Check for either file existing so it can be created on the other end
-        let meta: Vec<Metadata> = path
-            .iter().map(|x| metadata(x).unwrap()).collect();
-        let ftime: Vec<FileTime> = meta.iter()
-            .map(|x| FileTime::from_last_modification_time(&x)).collect();
+    for path in paths {
+        let meta = match metadata(path) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                files.push(json!({ "path": path, "size": null, "mtime": null, "same": false }));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Different sizes can't match, don't bother hashing.
+        let same = meta.len() == other_meta.len() && other_hash == calculate_hash(algorithm, settings.buffer_size, path)?;
 
-        if verbose {
-            println!("{} vs {}", path[0], path[1]);
-            println!("\tmtime: {} --- {}", ftime[0], ftime[1]);
-        }
+        if same { matching.push(path); }
+        files.push(json!({
+            "path": path,
+            "size": meta.len(),
+            "mtime": FileTime::from_last_modification_time(&meta).unix_seconds(),
+            "same": same,
+        }));
+    }
 
-        let (newest, oldest) = {
-            match ftime[0].cmp(&ftime[1]) {
-                Ordering::Greater => (0, 1),
-                Ordering::Less => (1, 0),
-                Ordering::Equal => {
-                    if verbose { println!("\t{}", FILES_THE_SAME); }
-                    continue;
+    // Every time a file of the pair got these contents, or lost them.
+    let mut versions: Vec<JSONValue> = Vec::new();
+    for event in history.iter().filter(|x| x.paths == paths) {
+        let change = if event.hash.as_ref() == Some(&other_hash) {
+            "written"
+        } else if event.replaced_hash.as_ref() == Some(&other_hash) {
+            "replaced"
+        } else {
+            continue;
+        };
+        versions.push(json!({ "time": event.time, "path": event.to, "change": change, "reason": event.reason }));
+    }
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&json!({
+            "other": other,
+            "size": other_meta.len(),
+            "mtime": FileTime::from_last_modification_time(&other_meta).unix_seconds(),
+            "hash": other_hash,
+            "files": files,
+            "history": versions,
+        })).unwrap()),
+        Output::Text => {
+            println!("{} vs {}", redact::path(other), redact::join(paths, " <-> "));
+            for (i, file) in files.iter().enumerate() {
+                let verdict = if file["same"] == true { "same contents" } else if file["size"].is_null() { "missing" } else { "differs" };
+                println!("\t#{} {}: {}", i + 1, redact::path(file["path"].as_str().unwrap_or("")), verdict);
+            }
+            match matching.len() {
+                0 => println!("\tmatches none of them"),
+                n if n == files.len() => println!("\tmatches all of them"),
+                _ => println!("\tmatches {}", redact::join(&matching, " and ")),
+            }
+            for version in &versions {
+                let time = locale::time(version["time"].as_u64().unwrap_or(0), settings.utc);
+                let path = redact::path(version["path"].as_str().unwrap_or(""));
+                match version["change"].as_str() {
+                    Some("written") => println!("\t{}: {} got these contents ({})", time, path, version["reason"].as_str().unwrap_or("")),
+                    _ => println!("\t{}: {} had these contents until then ({})", time, path, version["reason"].as_str().unwrap_or("")),
                 }
             }
+            if versions.is_empty() {
+                println!("\tnever seen in the history");
+            }
+        }
+    }
+
+    Ok(!matching.is_empty())
+}
+
+/// A copy in an atomic group, waiting for the rest of the group.
+struct GroupCopy<'a> {
+    entry: &'a config::Entry,
+    paths: &'a [String],
+    newest: usize,
+    oldest: usize,
+    mtime: FileTime,
+    /// Keep the file being replaced under another name, it lost a conflict.
+    keep: bool,
+    /// Why the file is replaced, for the history.
+    reason: String,
+}
+
+impl GroupCopy<'_> {
+    fn paths(&self) -> Vec<&str> {
+        config::as_strs(self.paths)
+    }
+}
+
+/// Carries out the copies of an atomic group: all of them are staged before any is moved into
+/// place, and none are if some entry of the group can't be updated.
+fn sync_group(settings: &Settings, state: &mut State, interval: Duration, group: &str, copies: &[GroupCopy], complete: bool) {
+    let hold = |state: &mut State, reason: &str| {
+        warning(Warning::Group, &format!("Not updating group \"{}\": {}", group, reason));
+        for copy in copies {
+            state.record(&copy.paths(), PassResult::Held);
+            state.skip(&copy.paths(), &format!("group \"{}\" isn't updated: {}", group, reason));
+        }
+    };
+
+    if !complete {
+        hold(state, "some of its files can't be updated right now");
+        return;
+    }
+
+    for copy in copies {
+        if let Err(e) = snapshot_side(state, copy.entry, copy.oldest) {
+            hold(state, &format!("couldn't take a snapshot: {}", e));
+            return;
+        }
+    }
+
+    let mut staged: Vec<PathBuf> = Vec::new();
+    for copy in copies {
+        let path = copy.paths();
+        let options = copy::CopyOptions {
+            direct_threshold: settings.direct_io,
+            temp_dir: copy.entry.temp_dir.as_deref(),
         };
 
-        let hash: Vec<String> = path.iter().map(|x| calculate_hash(buffer_size, x).unwrap()).collect();
-        let atime = FileTime::from_system_time(SystemTime::now());
-        if verbose { 
-            println!("\t#{} is newer. Checking hashes...", newest+1);
-            println!("\t{} vs {}", hash[0], hash[1]);
+        match timing::measure(copy.entry, "copy", || copy::stage(Path::new(path[copy.newest]), Path::new(path[copy.oldest]), &options)) {
+            Ok(s) => staged.push(s),
+            Err(e) => {
+                for s in &staged { let _ = fs::remove_file(s); }
+                hold(state, &format!("couldn't copy {}: {}", redact::path(path[copy.newest]), e));
+                failed(state, &path, interval, &e);
+                return;
+            }
         }
+    }
 
-        if hash[0] != hash[1] {
-            if verbose { println!("\tReplacing #{} with #{}", newest+1, oldest+1); }
-            copy(path[newest], path[oldest]).expect("Make sure you have permissions to copy!");
-            set_file_times(path[oldest], atime, ftime[newest]).expect("Make sure you have permission to modify timestamps!");
-            if !verbose { println!("Updated {}", path[oldest]); }
-        } else {
-            if verbose { println!("\t{}", FILES_THE_SAME); }
-            // Update filetime in that case so we don't waste time hashing again.
-            set_file_times(path[oldest], atime, ftime[newest]).expect("Make sure you have permission to modify timestamps!");
+    for copy in copies.iter().filter(|x| x.keep) {
+        let path = copy.paths();
+        match copy::keep_conflict(Path::new(path[copy.oldest])) {
+            Ok(kept) => println!("Kept {} as {}", redact::path(path[copy.oldest]), redact::path(kept)),
+            Err(e) => {
+                for s in &staged { let _ = fs::remove_file(s); }
+                hold(state, &format!("couldn't keep {}: {}", redact::path(path[copy.oldest]), e));
+                failed(state, &path, interval, &e);
+                return;
+            }
         }
     }
+
+    let atime = FileTime::from_system_time(SystemTime::now());
+    for (copy, staging) in copies.iter().zip(staged) {
+        let path = copy.paths();
+        let journaled = match metadata(path[copy.oldest]) {
+            Ok(m) => journal_change(state, &path, path[copy.oldest], FileTime::from_last_modification_time(&m), true, copy.entry.temp_dir.is_some()),
+            Err(_) => Ok(()),
+        };
+        let done = journaled.and_then(|_| copy::commit(&staging, Path::new(path[copy.oldest])))
+            .and_then(|_| set_file_times(path[copy.oldest], atime, copy.mtime));
+        if let Err(e) = done { failed(state, &path, interval, &e); continue; }
+        if !settings.verbose { println!("Updated {}", redact::path(path[copy.oldest])); }
+        log_update(state, &path, Some(copy.newest), copy.oldest, None, &copy.reason);
+        state.record(&path, PassResult::Updated);
+        check_churn(state, copy.entry, &path);
+        state.record_sync(&path, copy.mtime, None);
+    }
 }
 
-fn main() {
-    let (verbose, once, buffer_size, config, sleep_time) = match setup() {
-        Ok(v) => v,
-        Err(e) => error(&e.to_string())
+/// Warns the moment a pair was updated more often in the last hour than its entry allows, which
+/// usually means two programs are fighting over it, or it's synced around in a loop.
+fn check_churn(state: &mut State, entry: &config::Entry, path: &[&str]) {
+    let max = match entry.max_changes_per_hour {
+        Some(m) => m as usize,
+        None => return,
+    };
+
+    let updates = state.updates_in_last_hour(path);
+    if updates == max + 1 {
+        entry_warning(entry, Warning::Churn, &format!("{} were updated {} times in the last hour, something may be fighting over them", redact::join(path, " and "), updates));
+    }
+}
+
+/// Whether the file about to be replaced changed since the pair was last in sync, just like the
+/// one replacing it. Newest wins would silently throw away one of the changes.
+fn conflicting(entry: &config::Entry, pair: Option<&PairState>, mtime: &[FileTime], hash: Option<&[Option<String>]>, newest: usize, oldest: usize) -> bool {
+    let hash_of = |i: usize| hash.and_then(|x| x[i].as_deref());
+
+    // Going by contents alone, two files that differ without knowing what they were like before
+    // can't be told apart from two that both changed.
+    if entry.compare == Compare::Hash {
+        let synced = pair.and_then(|x| x.synced_hash.as_deref());
+        let changed = |i: usize| hash_of(i).is_some() && hash_of(i) != synced;
+        return changed(newest) && changed(oldest);
+    }
+
+    let pair = match pair {
+        Some(p) => p,
+        None => return false,
     };
+    let tolerance = entry.mtime_tolerance;
+    pair.changed(mtime[newest], hash_of(newest), tolerance) == Some(true) && pair.changed(mtime[oldest], hash_of(oldest), tolerance) == Some(true)
+}
 
+/// Asks whoever is at the terminal what to do about `path[newest]` and `path[oldest]` both changing,
+/// as the `on_conflict` for this once. Not getting an answer skips it.
+fn ask_conflict(settings: &Settings, path: &[&str], newest: usize, oldest: usize) -> OnConflict {
+    println!("Both {} and {} changed since the last sync.", redact::path(path[oldest]), redact::path(path[newest]));
     loop {
-        sync(verbose, buffer_size, &config);
-        if once { break }
-        sleep(sleep_time);
+        let answer = match init::prompt("Keep [l]eft, [r]ight, [b]oth, show the [d]iff or [s]kip? ") {
+            Ok(a) => a.to_lowercase(),
+            Err(_) => return OnConflict::Skip,
+        };
+        match answer.as_str() {
+            "l" | "left" => return OnConflict::Left,
+            "r" | "right" => return OnConflict::Right,
+            "b" | "both" => return OnConflict::KeepBoth,
+            "s" | "skip" | "" => return OnConflict::Skip,
+            "d" | "diff" => show_conflict(settings, path[oldest], path[newest]),
+            _ => println!("Answer l, r, b, d or s."),
+        }
+    }
+}
+
+/// Shows how `a` and `b` differ, line by line for text and by size and mtime for the rest.
+fn show_conflict(settings: &Settings, a: &str, b: &str) {
+    if let (Ok(x), Ok(y)) = (fs::read(a), fs::read(b)) {
+        if merge::is_text(&x) && merge::is_text(&y) {
+            let diff = merge::diff(std::str::from_utf8(&x).unwrap(), std::str::from_utf8(&y).unwrap());
+            match diff {
+                Some(d) => print!("--- {}\n+++ {}\n{}", redact::path(a), redact::path(b), d),
+                None => println!("They're too far apart to show line by line."),
+            }
+            return;
+        }
+    }
+
+    for path in [a, b] {
+        match metadata(path) {
+            Ok(m) => println!("\t{}: {} bytes, modified {}", redact::path(path), locale::number(m.len()), locale::time(FileTime::from_last_modification_time(&m).unix_seconds().max(0) as u64, settings.utc)),
+            Err(e) => println!("\t{}: {}", redact::path(path), e),
+        }
+    }
+}
+
+/// Merges the changes made to the files of a pair since it was last in sync into all of the ones
+/// that changed, if that's more than one: line by line with `merge`, for text that merges cleanly,
+/// or else with the entry's `conflict_command`. Returns whether it did, the rest of the pass then
+/// spreads the result like any other change.
+///
+/// Every file the merge would write has to get through the same checks as a copy over it, or
+/// nothing is merged at all. The result is staged next to each of them first, and they're only
+/// replaced once all of it is.
+fn merge_changes(config: &Config, state: &mut State, entry: &config::Entry, path: &[&str], meta: &[Option<Metadata>], ftime: &[FileTime], buffer_size: usize) -> Result<bool, Error> {
+    let synced_mtime = match state.pair(path).and_then(|x| x.synced_mtime) {
+        Some(m) => m,
+        None => return Ok(false),
+    };
+    let changed: Vec<usize> = (0..path.len()).filter(|&i| !same_mtime(ftime[i], synced_mtime, entry.mtime_tolerance)).collect();
+    if changed.len() < 2 { return Ok(false) }
+
+    let (merged, how) = match merge_lines(state, entry, path, meta, &changed)? {
+        Some(text) => (text.into_bytes(), "the changes to more than one file were merged"),
+        None => match run_conflict_command(config, state, entry, path, meta, ftime, buffer_size)? {
+            Some(data) => (data, "conflict_command merged the changes"),
+            None => return Ok(false),
+        },
+    };
+
+    let merged_paths: Vec<&str> = changed.iter().map(|&i| path[i]).collect();
+    let not_merging = |reason: &str| println!("Not merging the changes to {}: {}", redact::join(&merged_paths, " and "), reason);
+    let size: Vec<u64> = meta.iter().map(|x| x.as_ref().map_or(0, |m| m.len())).collect();
+    for &i in &changed {
+        if let Some(source) = entry.source().filter(|&x| x != i) {
+            not_merging(&format!("the entry only syncs from #{}", source + 1));
+            return Ok(false);
+        }
+        if let Some(reason) = config.write_refusal(Path::new(path[i])) {
+            not_merging(&reason);
+            return Ok(false);
+        }
+        if entry.git_guard && git::has_uncommitted_changes(Path::new(path[i])) {
+            not_merging(&format!("{} has uncommitted changes", redact::path(path[i])));
+            return Ok(false);
+        }
+        if let Some(ref policy) = entry.policy {
+            // What the policy sees is the newest of the other changes replacing this one.
+            let newest = changed.iter().copied().filter(|&x| x != i).max_by_key(|&x| ftime[x]).unwrap();
+            match policy.evaluate(path, &size, ftime, newest, i) {
+                Ok(Verdict::Copy) => {}
+                Ok(_) => {
+                    not_merging(&format!("policy doesn't let {} be replaced", redact::path(path[i])));
+                    return Ok(false);
+                }
+                Err(e) => {
+                    not_merging(&e);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    let staged: Vec<String> = changed.iter().map(|&i| format!("{}{}", path[i], artifacts::STAGING_SUFFIX)).collect();
+    let discard = || for file in &staged { let _ = fs::remove_file(file); };
+    let written = changed.iter().zip(&staged).try_for_each(|(&i, file)| {
+        fs::write(file, &merged)?;
+        if let Some(ref m) = meta[i] { fs::set_permissions(file, m.permissions())?; }
+        Ok(())
+    });
+    if let Err(e) = written {
+        discard();
+        return Err(e);
+    }
+
+    if let Some(ref check) = entry.verify_replace {
+        for (&i, file) in changed.iter().zip(&staged) {
+            match delta::suspicious(check, Path::new(path[i]), Path::new(file)) {
+                Ok(None) => {}
+                Ok(Some(reason)) => {
+                    discard();
+                    not_merging(&format!("{}: {}", redact::path(path[i]), reason));
+                    return Ok(false);
+                }
+                Err(e) => {
+                    discard();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    for &i in &changed {
+        if let Err(e) = snapshot_side(state, entry, i).and_then(|_| journal_change(state, path, path[i], ftime[i], true, false)) {
+            discard();
+            return Err(e);
+        }
+    }
+    let now = FileTime::now();
+    for (&i, file) in changed.iter().zip(&staged) {
+        fs::rename(file, path[i])?;
+        set_file_times(path[i], now, now)?;
+        log_update(state, path, None, i, None, how);
+    }
+
+    println!("Merged the changes to {}", redact::join(&merged_paths, " and "));
+    Ok(true)
+}
+
+/// What the `changed` files of a pair merge into line by line, if the entry merges them and they're
+/// text that merges cleanly with the base they were last in sync with.
+fn merge_lines(state: &State, entry: &config::Entry, path: &[&str], meta: &[Option<Metadata>], changed: &[usize]) -> Result<Option<String>, Error> {
+    let synced_hash = match state.pair(path).and_then(|x| x.synced_hash.as_deref()) {
+        Some(h) if entry.merge && state.has_base(h) => h,
+        _ => return Ok(None),
+    };
+    let small = |i: &usize| meta[*i].as_ref().is_some_and(|x| x.len() <= merge::MERGE_LIMIT);
+    if !changed.iter().all(small) { return Ok(None) }
+
+    // A base that can't be read just means there's nothing to merge with.
+    let base = match state.base(synced_hash).ok().filter(|x| merge::is_text(x)) {
+        Some(b) => String::from_utf8(b).unwrap(),
+        None => return Ok(None),
+    };
+    let mut merged = base.clone();
+    let mut sides = 0;
+    for &i in changed {
+        let data = fs::read(path[i])?;
+        if !merge::is_text(&data) { return Ok(None) }
+        let text = String::from_utf8(data).unwrap();
+        // Only touched, there's nothing to merge.
+        if text == base { continue }
+
+        merged = match merge::merge(&base, &merged, &text) {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        sides += 1;
+    }
+    Ok(if sides < 2 { None } else { Some(merged) })
+}
+
+/// What the entry's `conflict_command` merges the files of a pair into, if it has one and they
+/// really are in conflict, along with the base they were last in sync with when it was kept.
+fn run_conflict_command(config: &Config, state: &State, entry: &config::Entry, path: &[&str], meta: &[Option<Metadata>], ftime: &[FileTime], buffer_size: usize) -> Result<Option<Vec<u8>>, Error> {
+    let command = match entry.conflict_command {
+        Some(ref c) => c,
+        None => return Ok(None),
+    };
+    // Only touched, or changed the same way, isn't a conflict.
+    let hash = hashes(config.hash_algorithm, buffer_size, path, meta)?;
+    if hash[0] == hash[1] || !conflicting(entry, state.pair(path), ftime, Some(&hash), 0, 1) { return Ok(None) }
+
+    let base = state.pair(path).and_then(|x| x.synced_hash.as_deref()).filter(|x| state.has_base(x)).and_then(|x| state.base(x).ok());
+    let merged = merge::external(command, path[0], path[1], base.as_deref())?;
+    if merged.is_none() { println!("conflict_command didn't merge {}", redact::join(path, " and ")); }
+    Ok(merged)
+}
+
+/// Keeps what `file` looks like now that its pair is in sync, for merging the next changes to it.
+/// Returns its hash, or `hash` as it was if it can't be read.
+fn keep_base(state: &State, algorithm: Algorithm, path: &[&str], file: &str, mtime: FileTime, hash: Option<String>) -> Option<String> {
+    // Already taken care of when it got in sync.
+    let kept = state.pair(path).filter(|x| x.synced_mtime == Some(mtime)).and_then(|x| x.synced_hash.clone());
+    if kept.is_some() { return kept }
+
+    if metadata(file).map_or(true, |x| x.len() > merge::MERGE_LIMIT) { return hash }
+    let data = match fs::read(file) {
+        Ok(d) => d,
+        Err(_) => return hash,
+    };
+    let base_hash = algorithm.digest(&data);
+
+    if merge::is_text(&data) {
+        if let Err(e) = state.store_base(&base_hash, &data) {
+            warning(Warning::Failed, &format!("Couldn't keep {} for merging: {}", redact::path(file), e));
+        }
+    }
+    Some(base_hash)
+}
+
+/// Takes the entry's filesystem snapshot of side `side` the first time the pass is about to change
+/// something there. A failed snapshot holds back every change to that side for the rest of the
+/// pass.
+fn snapshot_side(state: &mut State, entry: &config::Entry, side: usize) -> Result<(), Error> {
+    let command = match entry.snapshot_command {
+        Some(ref c) => c,
+        None => return Ok(()),
+    };
+    let root = &entry.roots()[side];
+    if let Some(result) = state.snapshot(root) {
+        return match result {
+            Some(e) => Err(Error::other(e.clone())),
+            None => Ok(()),
+        };
+    }
+
+    let result = cow::take(command, root);
+    if result.is_ok() { println!("Took a snapshot of {}", redact::path(root)); }
+    state.record_snapshot(root, result.as_ref().err().map(|x| x.to_string()));
+    result
+}
+
+/// Notes in the undo journal that `file`, of the pair at `path`, is about to change, keeping its
+/// contents aside first unless only its mtime will. See `copy::back_up` for `in_place`.
+fn journal_change(state: &mut State, path: &[&str], file: &str, mtime: FileTime, contents: bool, in_place: bool) -> Result<(), Error> {
+    state.start_journal();
+    if state.journaled(file) { return Ok(()) }
+
+    let backup = if contents { Some(copy::back_up(Path::new(file), in_place)?) } else { None };
+    state.journal(Undo { paths: path.iter().map(|x| x.to_string()).collect(), file: file.to_string(), mtime, backup });
+    Ok(())
+}
+
+/// Writes down in the history that `path[to]` was just updated from `path[from]`, or had changes
+/// merged into it when there's no `from`.
+fn log_update(state: &State, path: &[&str], from: Option<usize>, to: usize, hash: Option<&[Option<String>]>, reason: &str) {
+    let hash_of = |i: usize| hash.and_then(|x| x[i].clone());
+    let event = history::Event {
+        time: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+        paths: path.iter().map(|x| x.to_string()).collect(),
+        from: from.map(|i| path[i].to_string()),
+        to: path[to].to_string(),
+        hash: from.and_then(hash_of),
+        replaced_hash: hash_of(to),
+        reason: reason.to_string(),
+    };
+    if let Err(e) = state.log(&event) {
+        warning(Warning::State, &format!("Couldn't add {} to the history: {}", redact::path(path[to]), e));
+    }
+}
+
+/// Drops the updates the retention rules don't keep from the history: ones older than `max_age`,
+/// all but the last `keep_versions` of each pair, and the oldest ones of an entry once its updates
+/// take up more than `max_size`. Updates of pairs no entry has anymore go by the config-wide rules.
+fn retain_history(config: &Config, state: &State) {
+    if !config.retention.is_set() && config.entries.iter().all(|x| !x.retention.is_set()) { return }
+
+    let events = match state.history() {
+        Ok(e) => e,
+        Err(e) => {
+            warning(Warning::State, &format!("Couldn't read the history to prune it: {}", e));
+            return;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let mut kept = vec![false; events.len()];
+    let mut versions: HashMap<&[String], usize> = HashMap::new();
+    let mut sizes: HashMap<Option<usize>, u64> = HashMap::new();
+
+    // Newest first, what goes over a limit is the oldest.
+    for (i, event) in events.iter().enumerate().rev() {
+        let owner = config.entries.iter().position(|x| x.covers(&event.paths));
+        let rules = owner.map_or(&config.retention, |x| &config.entries[x].retention);
+        let count = versions.entry(&event.paths).or_insert(0);
+        *count += 1;
+        let size = sizes.entry(owner).or_insert(0);
+        *size += event.to_json().to_string().len() as u64 + 1;
+
+        kept[i] = rules.max_age.is_none_or(|x| now.saturating_sub(event.time) <= x.as_secs())
+            && rules.keep_versions.is_none_or(|x| *count <= x)
+            && rules.max_size.is_none_or(|x| *size <= x);
+    }
+    if kept.iter().all(|x| *x) { return }
+
+    let events: Vec<history::Event> = events.into_iter().zip(kept).filter(|x| x.1).map(|x| x.0).collect();
+    if let Err(e) = state.set_history(&events) {
+        warning(Warning::State, &format!("Couldn't prune the history: {}", e));
+    }
+}
+
+/// Backs off from a pair that couldn't be synced, only complaining when the error is a new one.
+fn failed(state: &mut State, path: &[&str], interval: Duration, e: &Error) {
+    let message = e.to_string();
+    if state.record_failure(path, &message, interval) {
+        warning(Warning::Failed, &format!("Couldn't sync {}: {}", redact::join(path, " <-> "), message));
+    }
+}
+
+/// Finds files with the same contents in different entries, which usually means one of them points
+/// at the wrong file. Empty files are left out since they all match.
+fn duplicates(settings: &Settings, profiles: &[Profile]) -> bool {
+    let entries: Vec<&config::Entry> = profiles.iter().flat_map(|x| x.config.entries.iter()).collect();
+    // The hashes are only ever checked against each other, any algorithm does as long as it's one.
+    let algorithm = profiles.first().map_or_else(Algorithm::default, |x| x.config.hash_algorithm);
+    // Every synced file, along with the index of its entry.
+    let mut files: Vec<(usize, String)> = Vec::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut by_size: HashMap<u64, Vec<(usize, &str)>> = HashMap::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        match entry.pairs() {
+            Ok(pairs) => files.extend(pairs.into_iter().flatten().map(|x| (i, x))),
+            Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e)),
+        }
+    }
+
+    for (i, path) in &files {
+        if !seen.insert(path) { continue }
+        match metadata(path) {
+            Ok(meta) if meta.len() > 0 => by_size.entry(meta.len()).or_default().push((*i, path)),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warning(Warning::Failed, &format!("Couldn't check {}: {}", redact::path(path), e)),
+        }
+    }
+
+    let mut groups: Vec<Vec<&str>> = Vec::new();
+
+    // Only hash files that could match one from another entry.
+    for files in by_size.values().filter(|x| x.iter().any(|y| y.0 != x[0].0)) {
+        let mut by_hash: HashMap<String, Vec<(usize, &str)>> = HashMap::new();
+        for &(i, path) in files {
+            match calculate_hash(algorithm, settings.buffer_size, path) {
+                Ok(hash) => by_hash.entry(hash).or_default().push((i, path)),
+                Err(e) => warning(Warning::Failed, &format!("Couldn't hash {}: {}", redact::path(path), e)),
+            }
+        }
+
+        groups.extend(by_hash.into_values()
+            .filter(|x| x.iter().any(|y| y.0 != x[0].0))
+            .map(|x| x.into_iter().map(|y| y.1).collect()));
+    }
+
+    groups.sort();
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&groups).unwrap()),
+        Output::Text => {
+            for group in &groups {
+                println!("Same contents in different entries:");
+                for path in group { println!("\t{}", redact::path(path)); }
+            }
+        }
+    }
+
+    !groups.is_empty()
+}
+
+/// Reports the space taken by what staticsync keeps around, for each config and each of its
+/// entries, and prunes interrupted copies kept to resume by age or size if asked to.
+fn du(settings: &Settings, profiles: &[Profile]) {
+    let size_of = |path: &Path| metadata(path).map(|x| x.len()).unwrap_or(0);
+    // Sizes of the state, history and merge bases of each profile.
+    let mut sizes: Vec<[u64; 3]> = vec![[0; 3]; profiles.len()];
+    // Profile and entry, with the size of its trash and of its undo backups.
+    let mut kept: Vec<(usize, &config::Entry, u64, u64)> = Vec::new();
+    // Where the entry is in `kept`, destination, files left behind for it, their size and when they
+    // were last written to.
+    let mut leftovers: Vec<(usize, String, Vec<PathBuf>, u64, SystemTime)> = Vec::new();
+
+    for (i, profile) in profiles.iter().enumerate() {
+        look_as_owner(profile, |_| {
+            let state = &profile.state;
+            sizes[i] = [size_of(state.path()), size_of(&state.history_path()), walk::size(&state.bases_dir())];
+            let backups = state.undo_backups();
+
+            for entry in &profile.config.entries {
+                let trash = match entry.kind {
+                    Kind::Directories => entry.paths.iter().map(|x| walk::size(&Path::new(x).join(artifacts::TRASH_DIR))).sum(),
+                    _ => 0,
+                };
+                let undo = backups.iter().filter(|x| entry.covers(x.0)).map(|x| size_of(x.1)).sum();
+                kept.push((i, entry, trash, undo));
+
+                let pairs = match entry.pairs() {
+                    Ok(p) => p,
+                    Err(e) => {
+                        entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e));
+                        continue;
+                    }
+                };
+                for to in pairs.iter().flatten() {
+                    let files = copy::leftovers(Path::new(to), entry.temp_dir.as_deref());
+                    if files.is_empty() { continue }
+                    let size = files.iter().map(|x| size_of(x)).sum();
+                    let modified = files.iter().filter_map(|x| metadata(x).and_then(|m| m.modified()).ok())
+                        .max().unwrap_or(SystemTime::UNIX_EPOCH);
+                    leftovers.push((kept.len() - 1, to.to_string(), files, size, modified));
+                }
+            }
+        });
+    }
+
+    // Oldest first, so going over the budget gets rid of those.
+    leftovers.sort_by_key(|x| x.4);
+    let mut total: u64 = leftovers.iter().map(|x| x.3).sum();
+    let cutoff = settings.older_than.map(|x| SystemTime::now() - x);
+    let mut pruned: Vec<String> = Vec::new();
+
+    leftovers.retain(|(k, to, files, size, modified)| {
+        let too_old = cutoff.is_some_and(|x| *modified < x);
+        let over_budget = settings.budget.is_some_and(|x| total > x);
+        if !too_old && !over_budget { return true }
+
+        let removed = match look_as_owner(&profiles[kept[*k].0], |_| files.iter().try_for_each(fs::remove_file)) {
+            Some(r) => r,
+            None => return true,
+        };
+        match removed {
+            Ok(()) => {
+                total -= size;
+                pruned.push(to.clone());
+                false
+            }
+            Err(e) => {
+                warning(Warning::Failed, &format!("Couldn't remove the interrupted copy of {}: {}", redact::path(to), e));
+                true
+            }
+        }
+    });
+
+    let copies: Vec<JSONValue> = leftovers.iter()
+        .map(|(_, to, files, size, _)| json!({ "path": to, "files": files, "size": size })).collect();
+
+    let mut configs: Vec<JSONValue> = Vec::new();
+    for (i, profile) in profiles.iter().enumerate() {
+        let (state, history, bases) = (profile.state.path(), profile.state.history_path(), profile.state.bases_dir());
+        let entries: Vec<JSONValue> = kept.iter().enumerate().filter(|(_, x)| x.0 == i).map(|(k, &(_, entry, trash, undo))| {
+            let copied: u64 = leftovers.iter().filter(|x| x.0 == k).map(|x| x.3).sum();
+            json!({
+                "name": entry.name,
+                "paths": entry.paths,
+                "interrupted_copies": copied,
+                "trash": trash,
+                "undo_backups": undo,
+            })
+        }).collect();
+
+        configs.push(json!({
+            "config": profile.config.path,
+            "state": { "path": state, "size": sizes[i][0] },
+            "history": { "path": history, "size": sizes[i][1] },
+            "merge_bases": { "path": bases, "size": sizes[i][2] },
+            "entries": entries,
+        }));
+    }
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&json!({
+            "configs": configs,
+            "interrupted_copies": copies,
+            "pruned": pruned,
+        })).unwrap()),
+        Output::Text => {
+            let bytes = |value: &JSONValue| format!("{} bytes", locale::number(value.as_u64().unwrap_or(0)));
+            for config in &configs {
+                println!("{}", config["config"].as_str().unwrap_or(""));
+                println!("\tstate: {}", bytes(&config["state"]["size"]));
+                println!("\thistory: {}", bytes(&config["history"]["size"]));
+                println!("\tmerge bases: {}", bytes(&config["merge_bases"]["size"]));
+                for entry in config["entries"].as_array().unwrap() {
+                    let paths: Vec<&str> = entry["paths"].as_array().unwrap().iter().map(|x| x.as_str().unwrap()).collect();
+                    let label = entry["name"].as_str().map(String::from).unwrap_or_else(|| redact::join(&paths, " <-> "));
+                    println!("\t{}: {} in interrupted copies, {} in the trash, {} in undo backups", label,
+                        bytes(&entry["interrupted_copies"]), bytes(&entry["trash"]), bytes(&entry["undo_backups"]));
+                }
+            }
+            for copy in &copies {
+                println!("Interrupted copy of {}: {} bytes", redact::path(copy["path"].as_str().unwrap_or("")), locale::number(copy["size"].as_u64().unwrap_or(0)));
+            }
+            for path in &pruned {
+                println!("Removed the interrupted copy of {}", redact::path(path));
+            }
+        }
+    }
+}
+
+/// Metadata of every file of a pair, `None` for the ones that aren't there yet.
+fn stat(path: &[&str]) -> Result<Vec<Option<Metadata>>, Error> {
+    path.iter().map(|x| match metadata(x) {
+        Ok(m) => Ok(Some(m)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }).collect()
+}
+
+/// The mtime of every file of a pair. Files that aren't there count as older than any other, so
+/// they get created from the newest one.
+fn mtimes(meta: &[Option<Metadata>]) -> Vec<FileTime> {
+    meta.iter().map(|x| x.as_ref().map_or(FileTime::zero(), FileTime::from_last_modification_time)).collect()
+}
+
+/// Lists `items` the way pairs are shown, one value per file.
+fn joined<T: fmt::Display>(items: &[T], separator: &str) -> String {
+    items.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(separator)
+}
+
+/// Hashes of the files of a pair, none for the ones that aren't there.
+fn hashes(algorithm: Algorithm, buffer_size: usize, path: &[&str], meta: &[Option<Metadata>]) -> Result<Vec<Option<String>>, Error> {
+    path.iter().zip(meta)
+        .map(|(x, m)| m.as_ref().map(|_| calculate_hash(algorithm, buffer_size, x)).transpose())
+        .collect()
+}
+
+/// Whether two files have the same contents, reading them side by side and stopping at the first
+/// block that differs. Files of different sizes aren't read at all.
+fn same_contents(buffer_size: usize, a: &str, b: &str) -> Result<bool, Error> {
+    let (mut a, mut b) = match (File::open(a), File::open(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(ref e), _) | (_, Err(ref e)) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    if a.metadata()?.len() != b.metadata()?.len() { return Ok(false) }
+
+    let mut buf_a: Vec<u8> = vec![0; buffer_size];
+    let mut buf_b: Vec<u8> = vec![0; buffer_size];
+    loop {
+        let n = fill(&mut a, &mut buf_a)?;
+        if n != fill(&mut b, &mut buf_b)? || buf_a[..n] != buf_b[..n] { return Ok(false) }
+        if n < buf_a.len() { return Ok(true) }
+    }
+}
+
+/// Reads into `buf` until it's full or the file ends, returning how much was read.
+fn fill(file: &mut File, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn calculate_hash(algorithm: Algorithm, buffer_size: usize, path: &str) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = algorithm.hasher();
+    // Big files are read in big chunks, so the hasher has enough to spread over every core.
+    let size = file.metadata()?.len();
+    if hasher.parallel() && size >= hashing::PARALLEL_THRESHOLD {
+        let mut buf: Vec<u8> = vec![0; hashing::PARALLEL_BUFFER.min(size as usize).max(buffer_size)];
+        loop {
+            let n = fill(&mut file, &mut buf)?;
+            hasher.update_parallel(&buf[..n]);
+            if n < buf.len() { break }
+        }
+        return Ok(hasher.finish());
+    }
+
+    let mut buf: Vec<u8> = vec![0; buffer_size];
+    loop {
+        let n = file.read(&mut buf)?;
+        hasher.update(&buf[..n]);
+        if n == 0 || n < buf.len() { break }
+    }
+    
+    Ok(hasher.finish())
+}
+
+/// Re-hashes a random sample of the pairs that are in sync, to catch contents that changed behind
+/// our back without the mtime moving, like from bit rot.
+fn verify(settings: &Settings, profile: &mut Profile) {
+    let state = &profile.state;
+    let candidates: Vec<Vec<String>> = pairs_of(&profile.config).into_iter().map(|x| x.1)
+        .filter(|paths| {
+            let path = config::as_strs(paths);
+            let mtime: Vec<Option<FileTime>> = path.iter()
+                .map(|x| metadata(x).ok().map(|m| FileTime::from_last_modification_time(&m))).collect();
+            // Pairs with different mtimes changed for real, the next pass takes care of those.
+            state.pair(&path).and_then(|x| x.last_result) == Some(PassResult::InSync)
+                && mtime[0].is_some() && mtime.iter().all(|x| *x == mtime[0])
+        }).collect();
+
+    for paths in sample(candidates, profile.config.verify_sample) {
+        let path = config::as_strs(&paths);
+        let hash: Result<Vec<String>, Error> = path.iter().map(|x| calculate_hash(profile.config.hash_algorithm, settings.buffer_size, x)).collect();
+
+        match hash {
+            Ok(hash) => {
+                let ok = hash.iter().all(|x| *x == hash[0]);
+                if !ok {
+                    warning(Warning::Mismatch, &format!("{} have the same mtime but their contents differ", redact::join(&path, " and ")));
+                } else if settings.verbose {
+                    println!("Verified {}", redact::join(&path, " vs "));
+                }
+                profile.state.record_verification(&path, ok);
+            }
+            Err(e) => warning(Warning::Failed, &format!("Couldn't verify {}: {}", redact::join(&path, " vs "), e)),
+        }
+    }
+
+    if let Err(e) = profile.state.save() {
+        warning(Warning::State, &format!("Couldn't save sync state: {}", e));
+    }
+}
+
+/// Picks up to `n` of `items` at random.
+fn sample<T>(mut items: Vec<T>, n: usize) -> Vec<T> {
+    // A xorshift seeded from the clock is plenty random for this.
+    let mut seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0) | 1;
+    let n = n.min(items.len());
+
+    for i in 0..n {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let j = i + (seed % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+
+    items.truncate(n);
+    items
+}
+
+/// Moves the files of a pair whose golden copy isn't there to the trash, since they don't belong.
+fn remove_strays(config: &Config, entry: &config::Entry, path: &[&str], meta: &[Option<Metadata>], state: &mut State, interval: Duration) {
+    if entry.kind != Kind::Directories {
+        failed(state, path, interval, &Error::other("the golden copy is gone"));
+        return;
+    }
+
+    for i in (0..path.len()).filter(|&i| meta[i].is_some()) {
+        if let Some(reason) = config.write_refusal(Path::new(path[i])) {
+            entry_warning(entry, Warning::Refused, &format!("Not removing {}: {}", redact::path(path[i]), reason));
+            continue;
+        }
+
+        let removed = snapshot_side(state, entry, i).and_then(|_| to_trash(Path::new(&entry.paths[i]), path[i]));
+        match removed {
+            Ok(()) => entry_warning(entry, Warning::Drift, &format!("Moved {} to the trash, it isn't in the golden copy", redact::path(path[i]))),
+            Err(e) => { failed(state, path, interval, &e); return; }
+        }
+    }
+    state.forget(path);
+}
+
+/// Spots files that were renamed or moved on one side since the last pass and does the same on the
+/// other, instead of leaving the old name behind and copying the whole file over again.
+fn replay_renames(settings: &Settings, config: &Config, entry: &config::Entry, pairs: &mut Vec<Vec<String>>, lone: &mut Vec<Vec<String>>, state: &mut State) {
+    for old in state.known() {
+        if !entry.covers(&old) || pairs.contains(&old) { continue }
+
+        // Only a file missing from just one side can have been renamed there.
+        let gone: Vec<usize> = (0..old.len()).filter(|&i| !Path::new(&old[i]).exists()).collect();
+        if gone.len() != 1 { continue }
+        let side = gone[0];
+        let inode = state.pair(&config::as_strs(&old)).and_then(|x| x.inodes.get(side).copied());
+
+        let found = lone.iter().position(|new| {
+            (0..new.len()).all(|i| (i == side) == Path::new(&new[i]).exists())
+                && renamed(settings, config.hash_algorithm, &old, new, side, inode)
+        });
+        let new = match found {
+            Some(i) => lone.remove(i),
+            None => continue,
+        };
+
+        let mut moved = true;
+        for i in (0..old.len()).filter(|&i| i != side) {
+            if let Some(reason) = config.write_refusal(Path::new(&old[i])).or_else(|| config.write_refusal(Path::new(&new[i]))) {
+                entry_warning(entry, Warning::Refused, &format!("Not moving {}: {}", redact::path(&old[i]), reason));
+                moved = false;
+                break;
+            }
+
+            let result = snapshot_side(state, entry, i)
+                .and_then(|_| Path::new(&new[i]).parent().map_or(Ok(()), fs::create_dir_all))
+                .and_then(|_| fs::rename(&old[i], &new[i]));
+            match result {
+                Ok(()) => println!("Moved {} to {}, {} was renamed", redact::path(&old[i]), redact::path(&new[i]), redact::path(&old[side])),
+                Err(e) => {
+                    entry_warning(entry, Warning::Failed, &format!("Couldn't move {} to {}: {}", redact::path(&old[i]), redact::path(&new[i]), e));
+                    moved = false;
+                    break;
+                }
+            }
+        }
+
+        if moved {
+            state.rename(&config::as_strs(&old), &config::as_strs(&new));
+            pairs.push(new);
+        }
+    }
+}
+
+/// Whether `new[side]` is what used to be `old[side]`: it kept its inode, or failing that, it has
+/// the same contents as the file left on the other side.
+fn renamed(settings: &Settings, algorithm: Algorithm, old: &[String], new: &[String], side: usize, inode: Option<u64>) -> bool {
+    let meta = match metadata(&new[side]) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    if inode.is_some() && walk::inode(&meta) == inode { return true }
+
+    let other = if side == 0 { 1 } else { 0 };
+    metadata(&old[other]).is_ok_and(|x| x.len() == meta.len())
+        && matches!((calculate_hash(algorithm, settings.buffer_size, &old[other]), calculate_hash(algorithm, settings.buffer_size, &new[side])),
+            (Ok(a), Ok(b)) if a == b)
+}
+
+/// Deals with pairs of a directory entry that were synced before but weren't found this pass:
+/// records of pairs whose files are all gone are dropped, and if the entry mirrors deletions, files
+/// whose counterpart was deleted are deleted too. `current` are the pairs found this pass.
+fn prune_vanished(config: &Config, entry: &config::Entry, current: &[Vec<String>], state: &mut State) {
+    let roots: Vec<&Path> = entry.paths.iter().map(Path::new).collect();
+    let vanished: Vec<Vec<String>> = state.known().into_iter()
+        .filter(|x| x.len() == roots.len() && x.iter().zip(&roots).all(|(p, r)| Path::new(p).starts_with(r)))
+        .filter(|x| !current.contains(x))
+        .collect();
+    let mut orphaned: Vec<Vec<String>> = Vec::new();
+    // A side that's empty or gone looks like everything on it was deleted, but it's far more likely
+    // to be a drive that isn't mounted.
+    let empty = roots.iter().find(|x| fs::read_dir(x).map_or(true, |mut d| d.next().is_none()));
+    let expired = over_retention(entry, &roots, &vanished, state);
+
+    for paths in vanished {
+        let path = config::as_strs(&paths);
+        if let Some(waited) = state.in_trash(&path) {
+            settle_trash(entry, &path, waited, empty.is_none(), expired.contains(&paths), state);
+            continue;
+        }
+
+        match path.iter().filter(|x| Path::new(x).exists()).count() {
+            0 => state.forget(&path),
+            // Both still there, they're just not synced anymore (excluded, for one).
+            n if n == path.len() => {}
+            _ => orphaned.push(paths),
+        }
+    }
+
+    if orphaned.is_empty() || entry.delete == Delete::Off { return }
+
+    if let Some(root) = empty {
+        entry_warning(entry, Warning::EmptyRoot, &format!("Not deleting anything for {}: it's empty", redact::path(root)));
+        return;
+    }
+
+    for paths in orphaned {
+        let path = config::as_strs(&paths);
+        if entry.source().is_some_and(|x| Path::new(path[x]).exists()) { continue }
+        let gone = path.iter().find(|x| !Path::new(x).exists()).unwrap();
+        let mut trashed = false;
+
+        for (i, file) in path.iter().enumerate().filter(|(_, x)| Path::new(x).exists()) {
+            if let Some(reason) = config.write_refusal(Path::new(file)) {
+                entry_warning(entry, Warning::Refused, &format!("Not deleting {}: {}", redact::path(file), reason));
+                continue;
+            }
+            if entry.delete == Delete::On {
+                if let Err(e) = snapshot_side(state, entry, i) {
+                    entry_warning(entry, Warning::Failed, &format!("Not deleting {}: {}", redact::path(file), e));
+                    continue;
+                }
+            }
+
+            match (entry.delete, entry.delete_grace) {
+                (Delete::DryRun, _) => println!("Would delete {}, {} is gone", redact::path(file), redact::path(gone)),
+                (Delete::On, Some(_)) => match to_trash(roots[i], file) {
+                    Ok(()) => {
+                        println!("Moved {} to the trash, {} is gone", redact::path(file), redact::path(gone));
+                        trashed = true;
+                    }
+                    Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't move {} to the trash: {}", redact::path(file), e)),
+                },
+                (Delete::On, None) => match fs::remove_file(file) {
+                    Ok(()) => println!("Deleted {}, {} is gone", redact::path(file), redact::path(gone)),
+                    Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't delete {}: {}", redact::path(file), e)),
+                },
+                (Delete::Off, _) => {}
+            }
+        }
+
+        if trashed {
+            state.set_trashed(&path, true);
+        } else if path.iter().all(|x| !Path::new(x).exists()) {
+            state.forget(&path);
+        }
+    }
+}
+
+/// The pairs among `vanished` whose files have to leave the trash early, oldest in it first, for
+/// the trash of the entry to fit in its `max_size`.
+fn over_retention(entry: &config::Entry, roots: &[&Path], vanished: &[Vec<String>], state: &State) -> HashSet<Vec<String>> {
+    let mut expired = HashSet::new();
+    let max = match entry.retention.max_size {
+        Some(m) => m,
+        None => return expired,
+    };
+    let mut trashed: Vec<(&Vec<String>, Duration)> = vanished.iter()
+        .filter_map(|x| state.in_trash(&config::as_strs(x)).map(|y| (x, y))).collect();
+    if trashed.is_empty() { return expired }
+    trashed.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+    let mut total: u64 = roots.iter().map(|x| walk::size(&x.join(artifacts::TRASH_DIR))).sum();
+    for (paths, _) in trashed {
+        if total <= max { break }
+        let size: u64 = roots.iter().zip(paths).map(|(root, file)| metadata(trash_path(root, file)).map(|x| x.len()).unwrap_or(0)).sum();
+        total = total.saturating_sub(size);
+        expired.insert(paths.clone());
+    }
+    expired
+}
+
+/// Where `file`, under the directory `root` of an entry, goes while it's in the trash.
+fn trash_path(root: &Path, file: &str) -> PathBuf {
+    root.join(artifacts::TRASH_DIR).join(Path::new(file).strip_prefix(root).unwrap_or(Path::new(file)))
+}
+
+fn to_trash(root: &Path, file: &str) -> Result<(), Error> {
+    let trashed = trash_path(root, file);
+    let trash = root.join(artifacts::TRASH_DIR);
+    let fresh = !trash.exists();
+    if let Some(dir) = trashed.parent() { fs::create_dir_all(dir)?; }
+    if fresh { backups::exclude(&trash); }
+    fs::rename(file, trashed)
+}
+
+/// Removes the directories of the trash `trashed` was in once they're empty.
+fn tidy_trash(root: &Path, trashed: &Path) {
+    let trash = root.join(artifacts::TRASH_DIR);
+    for dir in trashed.ancestors().skip(1).take_while(|x| x.starts_with(&trash)) {
+        // All that's left in an empty trash is what keeps Spotlight out of it, if anything.
+        if dir == trash && fs::read_dir(dir).is_ok_and(|mut d| d.all(|x| x.is_ok_and(|x| x.file_name() == backups::SPOTLIGHT_MARKER))) {
+            let _ = fs::remove_file(dir.join(backups::SPOTLIGHT_MARKER));
+        }
+        if fs::remove_dir(dir).is_err() { break }
+    }
+}
+
+/// Deals with a pair whose files are in the trash: they go back where they were if any of the
+/// deleted ones came back, since that means they weren't really deleted, and they're deleted for
+/// good once they stayed gone for the whole grace period or as long as retention keeps them, or
+/// right away if they `expired`, as long as it's `safe` to tell.
+fn settle_trash(entry: &config::Entry, path: &[&str], waited: Duration, safe: bool, expired: bool, state: &mut State) {
+    let roots: Vec<&Path> = entry.paths.iter().map(Path::new).collect();
+    let back = path.iter().any(|x| Path::new(x).exists());
+
+    if back {
+        for (root, file) in roots.iter().zip(path) {
+            let trashed = trash_path(root, file);
+            if !trashed.exists() { continue }
+            // Something new took its place in the meantime, that's what counts now.
+            if Path::new(file).exists() {
+                if let Err(e) = fs::remove_file(&trashed) { entry_warning(entry, Warning::Failed, &format!("Couldn't empty {} from the trash: {}", redact::path(file), e)); }
+                continue;
+            }
+            match fs::rename(&trashed, file) {
+                Ok(()) => println!("Restored {} from the trash", redact::path(file)),
+                Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't restore {} from the trash: {}", redact::path(file), e)),
+            }
+            tidy_trash(root, &trashed);
+        }
+        state.set_trashed(path, false);
+    } else if safe && (expired || entry.delete_grace.is_none_or(|x| waited >= x) || entry.retention.max_age.is_some_and(|x| waited >= x)) {
+        for (root, file) in roots.iter().zip(path) {
+            let trashed = trash_path(root, file);
+            match fs::remove_file(&trashed) {
+                Ok(()) => {
+                    println!("Deleted {} for good", redact::path(file));
+                    tidy_trash(root, &trashed);
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => { entry_warning(entry, Warning::Failed, &format!("Couldn't empty {} from the trash: {}", redact::path(file), e)); return; }
+            }
+        }
+        state.forget(path);
+    }
+}
+
+/// Switches to `user`, if there is one, for as long as the guard is kept. Nothing of theirs may be
+/// touched if that fails, it's warned about and `Err` is returned.
+fn impersonate_owner(user: Option<&User>) -> Result<Option<users::Impersonation>, ()> {
+    match user {
+        Some(user) => users::impersonate(user).map(Some).map_err(|e| {
+            warning(Warning::User, &format!("Couldn't switch to user \"{}\": {}", user.name, e));
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Runs `f` with the privileges of the profile's user, if it has one. Returns what it did, unless
+/// it couldn't switch to them.
+fn run_as_owner<T, F: FnOnce(&mut Profile) -> T>(profile: &mut Profile, f: F) -> Option<T> {
+    let _guard = impersonate_owner(profile.user.as_ref()).ok()?;
+    Some(f(profile))
+}
+
+/// Like `run_as_owner`, for only looking at the profile and its files.
+fn look_as_owner<T, F: FnOnce(&Profile) -> T>(profile: &Profile, f: F) -> Option<T> {
+    let _guard = impersonate_owner(profile.user.as_ref()).ok()?;
+    Some(f(profile))
+}
+
+/// Runs a pass over the entries of `profile`, or only over the ones at the indices in `only`.
+fn sync(settings: &Settings, profile: &mut Profile, only: Option<&[usize]>) {
+    if settings.observe { return observe(settings, profile, only) }
+
+    for (i, e) in profile.config.resolve() {
+        entry_warning(&profile.config.entries[i], Warning::Invalid, &format!("Not syncing {}: {}", profile.config.entries[i].label(), e));
+    }
+
+    let verbose = settings.verbose;
+    let config = &profile.config;
+    let state = &mut profile.state;
+    let unavailable = &mut profile.unavailable;
+    let interval = profile.sleep_time;
+    let mut pairs: Vec<(&config::Entry, Vec<String>)> = Vec::new();
+    // Atomic groups with an entry left out of this pass, they can't be complete.
+    let mut incomplete: HashSet<&str> = HashSet::new();
+    // Copies held back until every pair of their atomic group is known to be good to go.
+    let mut grouped: HashMap<&str, Vec<GroupCopy>> = HashMap::new();
+    let mut ready: HashSet<&[String]> = HashSet::new();
+    // Made the first time a locked file is read, and deleted once the pass is over.
+    let mut shadows = vss::Shadows::default();
+
+    println!("Checking...");
+    let now = WeekTime::now();
+    for (i, entry) in config.entries.iter().enumerate() {
+        if only.is_some_and(|x| !x.contains(&i)) || !settings.selects(entry) { continue }
+
+        let entry_paths = config::as_strs(&entry.paths);
+        if let Some(problem) = entry.type_change() {
+            if unavailable.insert(i) {
+                entry_warning(entry, Warning::Invalid, &format!("Not syncing {}: {}", entry.label(), problem));
+            }
+            state.skip(&entry_paths, &problem);
+            if let Some(ref group) = entry.group { incomplete.insert(group); }
+            continue;
+        } else if let Some(unmet) = entry.unmet() {
+            if unavailable.insert(i) { println!("Putting off {}: {}", entry.label(), unmet); }
+            state.skip(&entry_paths, &format!("put off, {}", unmet));
+            if let Some(ref group) = entry.group { incomplete.insert(group); }
+            continue;
+        } else if entry.unavailable() {
+            if unavailable.insert(i) {
+                entry_warning(entry, Warning::Unavailable, &format!("Skipping {}: it isn't all there right now", entry.label()));
+            }
+            state.skip(&entry_paths, "it isn't all there right now");
+            if let Some(ref group) = entry.group { incomplete.insert(group); }
+            continue;
+        } else if unavailable.remove(&i) {
+            println!("{} is back", entry.label());
+        }
+
+        // It showed up but doesn't make sense, `resolve` already said why.
+        if entry.pending {
+            state.skip(&entry_paths, "it doesn't make sense now that it's there");
+            if let Some(ref group) = entry.group { incomplete.insert(group); }
+            continue;
+        }
+
+        if let Some(window) = config.pause(entry, now) {
+            if verbose { println!("{}: paused until {}", entry.label(), window.until()); }
+            state.skip(&entry_paths, &format!("paused until {}", window.until()));
+            if let Some(ref group) = entry.group { incomplete.insert(group); }
+            continue;
+        }
+
+        match timing::measure(entry, "scan", || entry.scan()) {
+            Ok((mut p, mut lone)) => {
+                if entry.kind == Kind::Directories {
+                    // A file renamed away from its golden copy drifted like any other change.
+                    if !entry.golden { replay_renames(settings, config, entry, &mut p, &mut lone, state); }
+                    prune_vanished(config, entry, &p, state);
+                }
+                // Files missing from some sides get created there, unless they're missing because
+                // they were deleted and deletions are mirrored. Only deletions from the source count
+                // for entries that sync one way. Files the golden copy doesn't have are always
+                // dealt with.
+                lone.retain(|x| entry.delete == Delete::Off || entry.golden || state.pair(&config::as_strs(x)).is_none()
+                    || entry.source().is_some_and(|s| Path::new(&x[s]).exists()));
+                pairs.extend(p.into_iter().chain(lone).map(|x| (entry, x)));
+            }
+            Err(e) => {
+                entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e));
+                state.skip(&entry_paths, &format!("couldn't list it: {}", e));
+                if let Some(ref group) = entry.group { incomplete.insert(group); }
+            }
+        }
+    }
+
+    // Entries with a higher priority go first. Within those, pairs that changed often in the past
+    // are likely to have changed again, check them first.
+    pairs.sort_by_key(|(entry, paths)| {
+        let paths = config::as_strs(paths);
+        let changes = state.pair(&paths).map(|x| x.changes).unwrap_or(0);
+        (std::cmp::Reverse(entry.priority), std::cmp::Reverse(changes))
+    });
+
+    'pairs: for (entry, paths) in &pairs {
+        let path = config::as_strs(paths);
+
+        if state.backing_off(&path) {
+            if verbose { println!("{}\n\tStill failing, backing off", redact::join(&path, " vs ")); }
+            state.skip(&path, "it keeps failing, backing off");
+            continue;
+        }
+
+        if let Some(ref only) = settings.explain {
+            if only.as_ref().is_none_or(|x| path.contains(&x.as_str()) || entry.paths.contains(x)) {
+                match explain::explain(config, entry, paths, state.pair(&path), settings.buffer_size) {
+                    Ok(e) => e.print(),
+                    Err(e) => entry_warning(entry, Warning::Failed, &format!("Couldn't explain {}: {}", path.join(" vs "), e)),
+                }
+            }
+        }
+
+        let mut meta = match timing::measure(entry, "stat", || stat(&path)) {
+            Ok(m) => m,
+            Err(e) => { failed(state, &path, interval, &e); continue; }
+        };
+        if meta.iter().all(Option::is_none) {
+            if verbose { println!("{}\n\tNone of the files are there", redact::join(&path, " vs ")); }
+            continue;
+        }
+        // Turned into a directory since it was listed, there's no telling what it's meant to be.
+        if let Some(i) = meta.iter().position(|x| x.as_ref().is_some_and(|x| !x.is_file())) {
+            failed(state, &path, interval, &Error::other(format!("\"{}\" isn't a file anymore", redact::path(path[i]))));
+            continue;
+        }
+        if entry.golden && meta[entry.master.unwrap()].is_none() {
+            remove_strays(config, entry, &path, &meta, state, interval);
+            continue;
+        }
+        let mut ftime = mtimes(&meta);
+        // What the files are read from, which is a shadow copy for ones another program keeps
+        // locked.
+        let shadowed: Option<Vec<String>> = if entry.vss {
+            match path.iter().map(|x| shadows.readable(x)).collect() {
+                Ok(p) => Some(p),
+                Err(e) => { failed(state, &path, interval, &e); continue; }
+            }
+        } else {
+            None
+        };
+        let read = shadowed.as_ref().map_or_else(|| path.clone(), |x| config::as_strs(x));
+
+        if entry.keeps_bases() && entry.master.is_none() {
+            let merged = merge_changes(config, state, entry, &path, &meta, &ftime, settings.buffer_size).and_then(|merged| if merged { stat(&path).map(Some) } else { Ok(None) });
+            match merged {
+                Ok(Some(m)) => {
+                    meta = m;
+                    ftime = mtimes(&meta);
+                }
+                Ok(None) => {}
+                Err(e) => { failed(state, &path, interval, &e); continue; }
+            }
+        }
+        if entry.kind == Kind::Directories && meta.iter().all(Option::is_some) {
+            state.set_inodes(&path, meta.iter().flatten().filter_map(walk::inode).collect());
+        }
+
+        if verbose {
+            println!("{}", redact::join(&path, " vs "));
+            println!("\tmtime: {}", joined(&ftime, " --- "));
+        }
+
+        let mut hash: Option<Vec<Option<String>>> = None;
+        let show_hashes = |h: &[Option<String>]| if verbose {
+            println!("\t{}", h.iter().map(|x| x.as_deref().unwrap_or("missing")).collect::<Vec<_>>().join(" vs "));
+        };
+        let decisions = match entry.compare {
+            Compare::Hash => timing::measure(entry, "hash", || hashes(config.hash_algorithm, settings.buffer_size, &read, &meta)).map(|h| {
+                show_hashes(&h);
+                let decisions = decide_by_hash(&h, state.pair(&path).and_then(|x| x.synced_hash.as_deref()), entry.master, &ftime);
+                hash = Some(h);
+                decisions
+            }),
+            Compare::Mtime => decide(&ftime, entry.master, entry.mtime_tolerance, |newest, oldest| {
+                if meta[oldest].is_none() { return Ok(false) }
+                if hash.is_none() {
+                    if verbose { println!("\t#{} is {}. Checking hashes...", newest+1, if entry.master.is_some() { "the master" } else { "newer" }); }
+                    let h = timing::measure(entry, "hash", || hashes(config.hash_algorithm, settings.buffer_size, &read, &meta))?;
+                    show_hashes(&h);
+                    hash = Some(h);
+                }
+                let h = hash.as_ref().unwrap();
+                Ok(h[newest] == h[oldest])
+            }),
+            Compare::Quick => decide(&ftime, entry.master, entry.mtime_tolerance, |_, _| Ok(false)),
+            Compare::Bytes => decide(&ftime, entry.master, entry.mtime_tolerance, |newest, oldest| {
+                if verbose { println!("\t#{} is {}. Comparing with #{}...", newest+1, if entry.master.is_some() { "the master" } else { "newer" }, oldest+1); }
+                timing::measure(entry, "compare", || same_contents(settings.buffer_size, read[newest], read[oldest]))
+            }),
+        };
+        let decisions = match decisions {
+            Ok(d) => d,
+            Err(e) => { failed(state, &path, interval, &e); continue; }
+        };
+        // Without hashes, there's no telling which one is right.
+        if entry.compare == Compare::Quick && matches!(decisions[..], [Decision::Unchanged])
+            && meta.iter().any(|x| x.as_ref().map(Metadata::len) != meta[0].as_ref().map(Metadata::len)) {
+            entry_warning(entry, Warning::Mismatch, &format!("Not syncing {}: they have the same mtime but not the same size", redact::join(&path, " and ")));
+            state.skip(&path, "same mtime, different sizes");
+            state.record(&path, PassResult::Held);
+            continue;
+        }
+
+        let atime = FileTime::from_system_time(SystemTime::now());
+        // What happened to each file that's behind, the pair is recorded once for all of them.
+        let mut results: Vec<PassResult> = Vec::new();
+        // Whether every file is up to date or about to be.
+        let mut good = true;
+        // Whether copies wait for the rest of their atomic group.
+        let mut deferred = false;
+        // The file all the others end up like.
+        let mut source = entry.master.unwrap_or_else(|| (0..ftime.len()).max_by_key(|&i| ftime[i]).unwrap_or(0));
+
+        for decision in decisions {
+            match decision {
+                Decision::Unchanged => {
+                    if verbose { println!("\t{}", FILES_THE_SAME); }
+                    results.push(PassResult::InSync);
+                }
+
+                Decision::Copy { newest, oldest } => {
+                    // Replicas don't get a say, whatever happened to them.
+                    let conflict = entry.master.is_none() && conflicting(entry, state.pair(&path), &ftime, hash.as_deref(), newest, oldest);
+                    // Which way the copy goes, which is against the mtimes when a conflict is settled
+                    // that way.
+                    let asked = conflict && entry.on_conflict == OnConflict::Skip && !settings.non_interactive && init::interactive();
+                    let on_conflict = if asked { ask_conflict(settings, &path, newest, oldest) } else { entry.on_conflict };
+                    let (newest, oldest) = match on_conflict {
+                        OnConflict::Skip if conflict => {
+                            entry_warning(entry, Warning::Conflict, &format!("Not replacing {}: it changed since the last sync, and so did {}", redact::path(path[oldest]), redact::path(path[newest])));
+                            state.skip(&path, "both sides changed since the last sync");
+                            state.record_conflict(&path);
+                            results.push(PassResult::Held);
+                            good = false;
+                            continue;
+                        }
+                        OnConflict::Left if conflict && newest != 0 => (oldest, newest),
+                        OnConflict::Right if conflict && newest != 1 => (oldest, newest),
+                        _ => (newest, oldest),
+                    };
+                    if entry.source().is_some_and(|x| x != newest) {
+                        if verbose { println!("\tNot replacing #{} with #{}: the entry only syncs from #{}", oldest+1, newest+1, entry.source().unwrap()+1); }
+                        state.skip(&path, &format!("#{} changed, but the entry only syncs from #{}", newest+1, entry.source().unwrap()+1));
+                        good = false;
+                        continue;
+                    }
+                    let keep = conflict && on_conflict == OnConflict::KeepBoth;
+                    source = newest;
+                    let reason = if entry.master.is_some() {
+                        format!("#{} is the master", newest+1)
+                    } else if conflict {
+                        format!("both changed since the last sync, {} picked #{}", if asked { "you" } else { "on_conflict" }, newest+1)
+                    } else if meta[oldest].is_none() {
+                        format!("#{} was missing", oldest+1)
+                    } else {
+                        format!("#{} is newer", newest+1)
+                    };
+
+                    if let Some(reason) = config.write_refusal(Path::new(path[oldest])) {
+                        entry_warning(entry, Warning::Refused, &format!("Not touching {}: {}", redact::path(path[oldest]), reason));
+                        state.skip(&path, &reason);
+                        good = false;
+                        continue;
+                    }
+
+                    if entry.git_guard && git::has_uncommitted_changes(Path::new(path[oldest])) {
+                        entry_warning(entry, Warning::Uncommitted, &format!("Not replacing {}: it has uncommitted changes", redact::path(path[oldest])));
+                        state.skip(&path, &format!("#{} has uncommitted changes", oldest+1));
+                        results.push(PassResult::Held);
+                        good = false;
+                        continue;
+                    }
+
+                    if let Some(ref policy) = entry.policy {
+                        let size: Vec<u64> = meta.iter().map(|x| x.as_ref().map_or(0, |m| m.len())).collect();
+                        match policy.evaluate(&path, &size, &ftime, newest, oldest) {
+                            Ok(Verdict::Copy) => {}
+                            Ok(Verdict::Skip) => {
+                                if verbose { println!("\tSkipped by policy"); }
+                                state.skip(&path, "policy skips it");
+                                good = false;
+                                continue;
+                            }
+                            Ok(Verdict::Conflict) => {
+                                entry_warning(entry, Warning::Policy, &format!("Not replacing {}: policy flagged a conflict", redact::path(path[oldest])));
+                                state.skip(&path, "policy flagged a conflict");
+                                results.push(PassResult::Held);
+                                good = false;
+                                continue;
+                            }
+                            Err(e) => {
+                                entry_warning(entry, Warning::Policy, &format!("Not replacing {}: {}", redact::path(path[oldest]), e));
+                                state.skip(&path, &e);
+                                good = false;
+                                continue;
+                            }
+                        }
+                    }
+
+                    if let (Some(ref check), Some(_)) = (&entry.verify_replace, &meta[oldest]) {
+                        match delta::suspicious(check, Path::new(path[oldest]), Path::new(path[newest])) {
+                            Ok(None) => {}
+                            Ok(Some(reason)) => {
+                                entry_warning(entry, Warning::Suspicious, &format!("Not replacing {}: {}", redact::path(path[oldest]), reason));
+                                state.skip(&path, &format!("#{}: {}", oldest+1, reason));
+                                results.push(PassResult::Held);
+                                good = false;
+                                continue;
+                            }
+                            Err(e) => { failed(state, &path, interval, &e); continue 'pairs; }
+                        }
+                    }
+
+                    if let Some(ref group) = entry.group {
+                        if verbose { println!("\tReplacing #{} with #{} along with group \"{}\"", oldest+1, newest+1, group); }
+                        grouped.entry(group).or_default().push(GroupCopy { entry, paths, newest, oldest, mtime: ftime[newest], keep, reason });
+                        deferred = true;
+                        continue;
+                    }
+
+                    if verbose { println!("\tReplacing #{} with #{}", oldest+1, newest+1); }
+                    if let Err(e) = snapshot_side(state, entry, oldest) {
+                        failed(state, &path, interval, &e);
+                        continue 'pairs;
+                    }
+                    if keep {
+                        match copy::keep_conflict(Path::new(path[oldest])) {
+                            Ok(kept) => println!("Kept {} as {}", redact::path(path[oldest]), redact::path(kept)),
+                            Err(e) => { failed(state, &path, interval, &e); continue 'pairs; }
+                        }
+                    }
+                    if meta[oldest].is_some() {
+                        if let Err(e) = journal_change(state, &path, path[oldest], ftime[oldest], true, entry.temp_dir.is_some()) {
+                            failed(state, &path, interval, &e);
+                            continue 'pairs;
+                        }
+                    }
+                    let options = copy::CopyOptions {
+                        direct_threshold: settings.direct_io,
+                        temp_dir: entry.temp_dir.as_deref(),
+                    };
+                    let copied = timing::measure(entry, "copy", || copy::copy(Path::new(read[newest]), Path::new(path[oldest]), &options))
+                        .and_then(|_| set_file_times(path[oldest], atime, ftime[newest]));
+                    if let Err(e) = copied { failed(state, &path, interval, &e); continue 'pairs; }
+                    if !verbose { println!("{} {}", if meta[oldest].is_some() { "Updated" } else { "Created" }, redact::path(path[oldest])); }
+                    // Filling in a golden entry for the first time isn't drift.
+                    if entry.golden && state.pair(&path).is_some_and(|x| x.synced_mtime.is_some()) {
+                        entry_warning(entry, Warning::Drift, &format!("Restored {} from the golden copy, it had drifted", redact::path(path[oldest])));
+                    }
+                    log_update(state, &path, Some(newest), oldest, hash.as_deref(), &reason);
+                    results.push(PassResult::Updated);
+                }
+
+                Decision::Touch { newest, oldest } => {
+                    if let Some(reason) = config.write_refusal(Path::new(path[oldest])) {
+                        entry_warning(entry, Warning::Refused, &format!("Not touching {}: {}", redact::path(path[oldest]), reason));
+                        state.skip(&path, &reason);
+                        good = false;
+                        continue;
+                    }
+
+                    if verbose { println!("\t#{}: {}", oldest+1, FILES_THE_SAME); }
+                    // Update filetime in that case so we don't waste time hashing again.
+                    let touched = snapshot_side(state, entry, oldest)
+                        .and_then(|_| journal_change(state, &path, path[oldest], ftime[oldest], false, false))
+                        .and_then(|_| set_file_times(path[oldest], atime, ftime[newest]));
+                    if let Err(e) = touched {
+                        failed(state, &path, interval, &e);
+                        continue 'pairs;
+                    }
+                    results.push(PassResult::InSync);
+                }
+            }
+        }
+
+        if good { ready.insert(paths); }
+        if good && !deferred {
+            let mut synced_hash = hash.as_ref().and_then(|x| x[source].clone());
+            if entry.keeps_bases() { synced_hash = keep_base(state, config.hash_algorithm, &path, path[source], ftime[source], synced_hash); }
+            state.record_sync(&path, ftime[source], synced_hash);
+        }
+        // A file that's held back is what needs attention, whatever happened to the others.
+        let result = [PassResult::Held, PassResult::Updated, PassResult::InSync].iter().find(|x| results.contains(x));
+        if let Some(&result) = result { state.record(&path, result); }
+        if result == Some(&PassResult::Updated) { check_churn(state, entry, &path); }
+    }
+
+    for (group, copies) in grouped {
+        let complete = !incomplete.contains(group) && pairs.iter()
+            .filter(|(entry, _)| entry.group.as_deref() == Some(group))
+            .all(|(_, paths)| ready.contains(&paths[..]));
+        sync_group(settings, state, interval, group, &copies, complete);
+    }
+
+    state.end_pass(|paths| config.entries.iter().enumerate()
+        .any(|(i, x)| only.is_none_or(|o| o.contains(&i)) && settings.selects(x) && (paths == &x.paths[..] || x.covers(paths))));
+    retain_history(config, state);
+
+    if let Err(e) = state.save() {
+        warning(Warning::State, &format!("Couldn't save sync state: {}", e));
+    }
+    if let Err(e) = timing::save() {
+        warning(Warning::Failed, &format!("Couldn't write the profile: {}", e));
+    }
+}
+
+/// Goes over the pairs of `profile` like a pass would, without changing any of the files. Pairs
+/// that differ are written down as skipped and warned about once, until they match again.
+fn observe(settings: &Settings, profile: &mut Profile, only: Option<&[usize]>) {
+    let config = &profile.config;
+    let state = &mut profile.state;
+    let diverged = &mut profile.diverged;
+
+    println!("Checking...");
+    for (i, entry) in config.entries.iter().enumerate() {
+        if only.is_some_and(|x| !x.contains(&i)) || !settings.selects(entry) { continue }
+
+        let entry_paths = config::as_strs(&entry.paths);
+        if entry.pending || entry.unavailable() || entry.type_change().is_some() {
+            state.skip(&entry_paths, "it isn't all there right now");
+            continue;
+        }
+        let pairs = match entry.scan() {
+            Ok((pairs, lone)) => pairs.into_iter().chain(lone),
+            Err(e) => {
+                entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e));
+                state.skip(&entry_paths, &format!("couldn't list it: {}", e));
+                continue;
+            }
+        };
+
+        for paths in pairs {
+            let path = config::as_strs(&paths);
+            let explanation = match explain::explain(config, entry, &paths, state.pair(&path), settings.buffer_size) {
+                Ok(e) => e,
+                Err(e) => {
+                    entry_warning(entry, Warning::Failed, &format!("Couldn't compare {}: {}", redact::join(&path, " and "), e));
+                    continue;
+                }
+            };
+
+            let differences: Vec<String> = explanation.decisions.iter()
+                .filter(|x| matches!(x, Decision::Copy { .. })).map(Decision::describe).collect();
+            if differences.is_empty() {
+                if diverged.remove(&paths) { println!("{} match again", redact::join(&path, " and ")); }
+                continue;
+            }
+
+            let what = differences.join(", ");
+            state.skip(&path, &format!("only observing, a pass would {}", what));
+            if diverged.insert(paths.clone()) {
+                entry_warning(entry, Warning::Diverged, &format!("{} differ, a pass would {}", redact::join(&path, " and "), what));
+            }
+        }
+    }
+
+    state.end_pass(|paths| config.entries.iter().enumerate()
+        .any(|(i, x)| only.is_none_or(|o| o.contains(&i)) && settings.selects(x) && (paths == &x.paths[..] || x.covers(paths))));
+
+    if let Err(e) = state.save() {
+        warning(Warning::State, &format!("Couldn't save sync state: {}", e));
+    }
+}
+
+/// Compares every pair without touching anything. Returns whether any of them differ.
+fn diff(settings: &Settings, config: &Config) -> bool {
+    let mut any_differ = false;
+
+    for (entry, paths) in pairs_of(config) {
+        if !settings.selects(entry) { continue }
+        let path = config::as_strs(&paths);
+        let missing: Vec<&str> = path.iter().filter(|x| !Path::new(x).exists()).cloned().collect();
+        if !missing.is_empty() {
+            println!("{}: differ\n\tmissing: {}", redact::join(&path, " vs "), redact::join(&missing, ", "));
+            any_differ = true;
+            continue;
+        }
+
+        // Unreadable, or gone since, a check that can't be made doesn't pass either.
+        let cant_check = |e: Error| {
+            println!("{}: couldn't check\n\t{}", redact::join(&path, " vs "), e);
+        };
+        let meta: Vec<Metadata> = match path.iter().map(metadata).collect() {
+            Ok(m) => m,
+            Err(e) => { cant_check(e); any_differ = true; continue; }
+        };
+        let not_files: Vec<&str> = path.iter().zip(&meta).filter(|x| !x.1.is_file()).map(|x| *x.0).collect();
+        if !not_files.is_empty() {
+            println!("{}: differ\n\tnot files: {}", redact::join(&path, " vs "), redact::join(&not_files, ", "));
+            any_differ = true;
+            continue;
+        }
+        let ftime: Vec<FileTime> = meta.iter().map(FileTime::from_last_modification_time).collect();
+        let hash: Vec<String> = match path.iter().map(|x| calculate_hash(config.hash_algorithm, settings.buffer_size, x)).collect() {
+            Ok(h) => h,
+            Err(e) => { cant_check(e); any_differ = true; continue; }
+        };
+        let mut reasons: Vec<String> = Vec::new();
+
+        let size: Vec<u64> = meta.iter().map(|x| x.len()).collect();
+        if size.iter().any(|x| *x != size[0]) {
+            reasons.push(format!("size: {}", joined(&size, " --- ")));
+        }
+
+        if ftime.iter().any(|x| *x != ftime[0]) {
+            reasons.push(format!("mtime: {}", joined(&ftime, " --- ")));
+        }
+
+        if hash.iter().any(|x| *x != hash[0]) {
+            reasons.push(format!("hash: {}", hash.join(" --- ")));
+        }
+
+        if reasons.is_empty() {
+            if settings.verbose { println!("{}: same", redact::join(&path, " vs ")); }
+        } else {
+            println!("{}: differ", redact::join(&path, " vs "));
+            for reason in reasons { println!("\t{}", reason); }
+            any_differ = true;
+        }
+    }
+
+    any_differ
+}
+
+/// Replays the decisions a pass would make given the metadata in a snapshot.
+fn simulate(config: &Config, snapshot: &Snapshot) {
+    for (entry, paths) in pairs_of(config) {
+        let path = config::as_strs(&paths);
+        print!("{}: ", redact::join(&path, " vs "));
+
+        let records: Vec<&snapshot::FileRecord> = path.iter().filter_map(|x| snapshot.files.get(*x)).collect();
+        if records.len() != path.len() {
+            println!("not in the snapshot");
+            continue;
+        }
+
+        let mtime: Vec<FileTime> = records.iter().map(|x| x.mtime).collect();
+        let decisions = match entry.compare {
+            Compare::Hash if records.iter().any(|x| x.hash.is_none()) => Err(Error::other("the entry compares by hash but the snapshot has none")),
+            Compare::Hash => Ok(decide_by_hash(&records.iter().map(|x| x.hash.clone()).collect::<Vec<_>>(), None, entry.master, &mtime)),
+            Compare::Mtime | Compare::Bytes => decide(&mtime, entry.master, entry.mtime_tolerance, |newest, oldest| {
+                match (&records[newest].hash, &records[oldest].hash) {
+                    (Some(a), Some(b)) => Ok(a == b),
+                    _ if records[newest].size != records[oldest].size => Ok(false),
+                    _ => Err(Error::other("sizes match but the snapshot has no hashes")),
+                }
+            }),
+            Compare::Quick => decide(&mtime, entry.master, entry.mtime_tolerance, |_, _| Ok(false)),
+        };
+
+        match decisions {
+            Ok(decisions) => {
+                let outcomes: Vec<String> = decisions.iter().map(|decision| {
+                    let refusal = match *decision {
+                        Decision::Copy { oldest, .. } | Decision::Touch { oldest, .. } =>
+                            config.write_refusal(Path::new(path[oldest])),
+                        Decision::Unchanged => None,
+                    };
+
+                    match refusal {
+                        Some(reason) => format!("would {}, but {}", decision.describe(), reason),
+                        None => format!("would {}", decision.describe()),
+                    }
+                }).collect();
+                println!("{}", outcomes.join("; "));
+            }
+            Err(e) => println!("can't tell, {}", e),
+        }
+    }
+}
+
+/// Fixes up the state of a profile and fills in pairs it knows nothing about with a fresh scan.
+fn state_repair(settings: &Settings, profile: &mut Profile) {
+    let pairs = pairs_of(&profile.config);
+    let config = &profile.config;
+    let algorithm = config.hash_algorithm;
+    let fixed = profile.state.repair(|x| config.covers(x));
+    let mut scanned = 0;
+
+    for (entry, paths) in &pairs {
+        let path = config::as_strs(paths);
+        if profile.state.pair(&path).is_some() || path.iter().any(|x| !Path::new(x).is_file()) { continue }
+
+        // A damaged tree is likely to have files that can't be read, the rest can still be
+        // repaired.
+        let decisions = path.iter().map(|x| metadata(x).map(|m| FileTime::from_last_modification_time(&m)))
+            .collect::<Result<Vec<FileTime>, Error>>()
+            .and_then(|ftime| decide(&ftime, None, entry.mtime_tolerance, |newest, oldest| {
+                Ok(calculate_hash(algorithm, settings.buffer_size, path[newest])? == calculate_hash(algorithm, settings.buffer_size, path[oldest])?)
+            }));
+        let decisions = match decisions {
+            Ok(d) => d,
+            Err(e) => {
+                warning(Warning::Failed, &format!("Couldn't check {}, leaving it out: {}", redact::join(&path, " vs "), e));
+                continue;
+            }
+        };
+
+        if decisions.iter().all(|x| matches!(x, Decision::Unchanged | Decision::Touch { .. })) {
+            profile.state.record(&path, PassResult::InSync);
+            scanned += 1;
+        }
+    }
+
+    println!("\"{}\": fixed {} record(s), found {} pair(s) in sync", profile.config.path.display(), fixed, scanned);
+    if let Err(e) = profile.state.save() {
+        error(&format!("Couldn't save sync state: {}", e));
+    }
+}
+
+/// Puts back what the last pass that changed anything did to the files of `profile`, and holds the
+/// pairs like conflicts so the next pass doesn't just do it again.
+fn undo(profile: &mut Profile) {
+    let journal = profile.state.take_journal();
+    if journal.is_empty() {
+        println!("\"{}\": nothing to undo", profile.config.path.display());
+        return;
+    }
+
+    for undo in journal.iter().rev() {
+        let restored = match undo.backup {
+            Some(ref backup) => fs::rename(backup, &undo.file).and_then(|_| filetime::set_file_mtime(&undo.file, undo.mtime)),
+            None => filetime::set_file_mtime(&undo.file, undo.mtime),
+        };
+        match restored {
+            Ok(()) => println!("Restored {}", redact::path(&undo.file)),
+            Err(e) => warning(Warning::Failed, &format!("Couldn't restore {}: {}", redact::path(&undo.file), e)),
+        }
+        profile.state.unsync(&config::as_strs(&undo.paths));
+    }
+
+    if let Err(e) = profile.state.save() {
+        error(&format!("Couldn't save sync state: {}", e));
+    }
+}
+
+/// What a conflict goes by on the command line, the same for as long as the pair is held.
+fn conflict_id(paths: &[String]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input_str(&paths.join("\n"));
+    hasher.result_str()[..8].to_string()
+}
+
+/// Lists the pairs queued as conflicts, oldest first, with what each of their files is like now.
+fn conflicts(settings: &Settings, profiles: &[Profile]) {
+    let mut found: Vec<JSONValue> = Vec::new();
+    for profile in profiles {
+        look_as_owner(profile, |profile| {
+            for (paths, since) in profile.state.conflicts() {
+                let files: Vec<JSONValue> = paths.iter().map(|path| match metadata(path) {
+                    Ok(meta) => json!({ "path": path, "size": meta.len(), "mtime": FileTime::from_last_modification_time(&meta).unix_seconds() }),
+                    Err(_) => json!({ "path": path, "size": null, "mtime": null }),
+                }).collect();
+                found.push(json!({ "id": conflict_id(paths), "config": profile.config.path, "since": since, "files": files }));
+            }
+        });
+    }
+    found.sort_by_key(|x| x["since"].as_u64());
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&found).unwrap()),
+        Output::Text => {
+            for conflict in &found {
+                println!("{}: held since {}", conflict["id"].as_str().unwrap_or(""), locale::time(conflict["since"].as_u64().unwrap_or(0), settings.utc));
+                for (i, file) in conflict["files"].as_array().unwrap().iter().enumerate() {
+                    let path = redact::path(file["path"].as_str().unwrap_or(""));
+                    match (file["size"].as_u64(), file["mtime"].as_i64()) {
+                        (Some(size), Some(mtime)) => println!("\t#{} {}: {} bytes, modified {}", i + 1, path, locale::number(size), locale::time(mtime.max(0) as u64, settings.utc)),
+                        _ => println!("\t#{} {}: missing", i + 1, path),
+                    }
+                }
+            }
+            if found.is_empty() { println!("No conflicts"); }
+        }
+    }
+}
+
+/// Settles the conflict `id` by hand, making the other files of its pair like the left one, the
+/// right one, or the newest while keeping them under another name, like `on_conflict` would. It's
+/// the user's call, so only what nothing may ever write to still stands in the way; `undo` takes it
+/// back.
+fn resolve(settings: &Settings, profile: &mut Profile, id: &str, keep: OnConflict) -> Result<(), Error> {
+    let config = &profile.config;
+    let state = &mut profile.state;
+    let paths: Vec<String> = match state.conflicts().into_iter().find(|x| conflict_id(x.0) == id) {
+        Some(x) => x.0.to_vec(),
+        None => return Err(Error::other("it's gone")),
+    };
+    let entry = match config.entries.iter().find(|x| x.covers(&paths)) {
+        Some(e) => e,
+        None => return Err(Error::other("no entry syncs it anymore")),
+    };
+    let path = config::as_strs(&paths);
+    let meta = stat(&path)?;
+    let ftime = mtimes(&meta);
+    let source = match keep {
+        OnConflict::Left => 0,
+        OnConflict::Right => path.len() - 1,
+        _ => (0..path.len()).max_by_key(|&i| ftime[i]).unwrap_or(0),
+    };
+
+    if meta[source].is_none() { return Err(Error::other(format!("{} is gone", redact::path(path[source])))) }
+    if let Some(from) = entry.source().filter(|&x| x != source) {
+        return Err(Error::other(format!("the entry only syncs from #{}", from + 1)));
+    }
+    let others: Vec<usize> = (0..path.len()).filter(|&i| i != source).collect();
+    // Nothing is changed unless all of them can be.
+    for &i in &others {
+        if let Some(reason) = config.write_refusal(Path::new(path[i])) {
+            return Err(Error::other(format!("not touching {}: {}", redact::path(path[i]), reason)));
+        }
+    }
+
+    let reason = format!("conflict resolved by hand, kept #{}", source + 1);
+    let atime = FileTime::from_system_time(SystemTime::now());
+    let options = copy::CopyOptions { direct_threshold: settings.direct_io, temp_dir: entry.temp_dir.as_deref() };
+    for &i in &others {
+        snapshot_side(state, entry, i)?;
+        if meta[i].is_some() {
+            if keep == OnConflict::KeepBoth {
+                let kept = copy::keep_conflict(Path::new(path[i]))?;
+                println!("Kept {} as {}", redact::path(path[i]), redact::path(kept));
+            }
+            journal_change(state, &path, path[i], ftime[i], true, entry.temp_dir.is_some())?;
+        }
+        copy::copy(Path::new(path[source]), Path::new(path[i]), &options)?;
+        set_file_times(path[i], atime, ftime[source])?;
+        println!("Updated {}", redact::path(path[i]));
+        log_update(state, &path, Some(source), i, None, &reason);
+    }
+
+    let mut synced_hash = calculate_hash(config.hash_algorithm, settings.buffer_size, path[source]).ok();
+    if entry.keeps_bases() { synced_hash = keep_base(state, config.hash_algorithm, &path, path[source], ftime[source], synced_hash); }
+    state.record_sync(&path, ftime[source], synced_hash);
+    state.record(&path, PassResult::Updated);
+    state.save()
+}
+
+/// Lists the files that were updated, or only the ones at or under `path`, oldest first.
+fn history(settings: &Settings, profiles: &[Profile], path: Option<&str>) {
+    let mut events: Vec<history::Event> = Vec::new();
+    for profile in profiles {
+        match look_as_owner(profile, |profile| profile.state.history()) {
+            Some(Ok(e)) => events.extend(e),
+            Some(Err(e)) => error(&format!("Couldn't read the history of \"{}\": {}", profile.config.path.display(), e)),
+            None => {}
+        }
+    }
+    if let Some(path) = path { events.retain(|x| x.concerns(Path::new(path))); }
+    events.sort_by_key(|x| x.time);
+
+    match settings.output {
+        Output::Json => {
+            let events: Vec<JSONValue> = events.iter().map(|x| x.to_json()).collect();
+            println!("{}", serde_json::to_string_pretty(&events).unwrap());
+        }
+        Output::Text => {
+            for event in events {
+                match event.from {
+                    Some(ref from) => println!("{}: {} updated from {}", locale::time(event.time, settings.utc), redact::path(&event.to), redact::path(from)),
+                    None => println!("{}: {} merged", locale::time(event.time, settings.utc), redact::path(&event.to)),
+                }
+                println!("\t{}", event.reason);
+                if event.hash.is_some() || event.replaced_hash.is_some() {
+                    println!("\t{} -> {}", event.replaced_hash.as_deref().unwrap_or("?"), event.hash.as_deref().unwrap_or("?"));
+                }
+            }
+        }
+    }
+}
+
+/// Drops the records of pairs that no longer matter: not configured anymore, with none of their
+/// files left, or left out of their entry while they're still there. Returns how many went.
+fn compact(profile: &mut Profile) -> usize {
+    let mut listed: HashSet<Vec<String>> = HashSet::new();
+    // Entries that can't be looked at right now keep all of their records, a drive that isn't
+    // mounted doesn't mean its files are gone.
+    let mut unknown: Vec<&config::Entry> = Vec::new();
+    for entry in &profile.config.entries {
+        match entry.scan() {
+            Ok((pairs, lone)) if !entry.pending && !entry.unavailable() => listed.extend(pairs.into_iter().chain(lone)),
+            _ => unknown.push(entry),
+        }
+    }
+
+    let state = &profile.state;
+    let stale: Vec<Vec<String>> = state.known().into_iter()
+        .filter(|x| !listed.contains(x) && !unknown.iter().any(|e| e.covers(x)))
+        .filter(|x| state.in_trash(&config::as_strs(x)).is_none())
+        .collect();
+    for paths in &stale {
+        profile.state.forget(&config::as_strs(paths));
+    }
+    stale.len()
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Prints the sync state of every pair as CSV.
+fn export(profiles: &[Profile]) {
+    println!("config,path_a,path_b,changes,last_result,last_update,other_paths");
+
+    for profile in profiles {
+        look_as_owner(profile, |profile| {
+            for (_, paths) in pairs_of(&profile.config) {
+                let path = config::as_strs(&paths);
+                let pair = profile.state.pair(&path);
+                let fields = [
+                    profile.config.path.to_string_lossy().into_owned(),
+                    path[0].to_string(),
+                    path[1].to_string(),
+                    pair.map(|x| x.changes).unwrap_or(0).to_string(),
+                    pair.and_then(|x| x.last_result).map(|x| x.as_str()).unwrap_or("").to_string(),
+                    pair.and_then(|x| x.last_update).map(|x| x.to_string()).unwrap_or_default(),
+                    path[2..].join(" <-> "),
+                ];
+                let line: Vec<String> = fields.iter().map(|x| csv_field(x)).collect();
+                println!("{}", line.join(","));
+            }
+        });
+    }
+}
+
+/// Sums up the last results of the pairs of each profile.
+fn status(settings: &Settings, profiles: &[Profile]) {
+    let mut summaries: Vec<JSONValue> = Vec::new();
+
+    for profile in profiles {
+        let known = profile.state.known();
+        let count = |result: PassResult| known.iter()
+            .filter(|x| profile.state.pair(&config::as_strs(x)).and_then(|x| x.last_result) == Some(result)).count();
+
+        summaries.push(json!({
+            "config": profile.config.path,
+            // Whose passes they are, in system mode.
+            "user": profile.user.as_ref().map(|x| &x.name),
+            "pairs": known.len(),
+            "in_sync": count(PassResult::InSync),
+            "updated": count(PassResult::Updated),
+            "held": count(PassResult::Held),
+            "failed": count(PassResult::Failed),
+            "skipped": profile.state.skipped().len(),
+            "stopped_by": profile.config.stop_file.as_ref().filter(|x| x.exists()),
+        }));
+    }
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&summaries).unwrap()),
+        Output::Text => {
+            for summary in summaries {
+                match summary["user"].as_str() {
+                    Some(user) => println!("{} (user {})", summary["config"].as_str().unwrap_or(""), user),
+                    None => println!("{}", summary["config"].as_str().unwrap_or("")),
+                }
+                println!("\t{} pair(s): {} in sync, {} updated, {} held, {} failed",
+                    summary["pairs"], summary["in_sync"], summary["updated"], summary["held"], summary["failed"]);
+                if summary["skipped"] != 0 {
+                    println!("\t{} thing(s) skipped in the last pass, see status --skipped", summary["skipped"]);
+                }
+                if let Some(stop_file) = summary["stopped_by"].as_str() {
+                    println!("\tstopped, remove \"{}\" to carry on", stop_file);
+                }
+            }
+        }
+    }
+}
+
+/// Lists what the last pass over each entry left alone, and why.
+fn list_skipped(settings: &Settings, profiles: &[Profile]) {
+    let skipped: Vec<JSONValue> = profiles.iter()
+        .flat_map(|profile| profile.state.skipped().iter().map(move |x| json!({
+            "config": profile.config.path,
+            "paths": x.paths,
+            // When the whole of a named entry was skipped.
+            "name": profile.config.entries.iter().find(|e| e.paths == x.paths).and_then(|e| e.name.clone()),
+            "reason": x.reason,
+        })))
+        .collect();
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&skipped).unwrap()),
+        Output::Text => {
+            for skip in skipped {
+                let paths: Vec<&str> = skip["paths"].as_array().unwrap().iter().map(|x| x.as_str().unwrap()).collect();
+                match skip["name"].as_str() {
+                    Some(name) => println!("{}", name),
+                    None => println!("{}", redact::join(&paths, " <-> ")),
+                }
+                println!("\t{}", skip["reason"].as_str().unwrap_or(""));
+            }
+        }
+    }
+}
+
+/// Prints every configured entry along with what we know about it.
+fn list(settings: &Settings, profiles: &[Profile]) {
+    let mut entries: Vec<JSONValue> = Vec::new();
+    let now = WeekTime::now();
+
+    for profile in profiles {
+        look_as_owner(profile, |profile| {
+            let config = &profile.config;
+            let stopped = config.stop_file.as_ref().is_some_and(|x| x.exists());
+
+            for entry in &config.entries {
+                let window = config.pause(entry, now);
+                // The same order a pass checks these in, so it's what the next one would say.
+                let state = if stopped {
+                    "stopped"
+                } else if entry.type_change().is_some() {
+                    "invalid"
+                } else if entry.unmet().is_some() {
+                    "put off"
+                } else if entry.unavailable() {
+                    "unavailable"
+                } else if entry.pending {
+                    "pending"
+                } else if window.is_some() {
+                    "paused"
+                } else {
+                    "active"
+                };
+
+                let mut pairs: Vec<JSONValue> = Vec::new();
+                let listed = match entry.pairs() {
+                    Ok(p) if !entry.pending => p,
+                    Err(e) if !entry.pending && !entry.unavailable() => {
+                        entry_warning(entry, Warning::Failed, &format!("Couldn't list {}: {}", entry.label(), e));
+                        Vec::new()
+                    }
+                    // Nothing to go through yet, but the entry itself is still worth showing.
+                    _ => Vec::new(),
+                };
+                for paths in listed {
+                    let path = config::as_strs(&paths);
+                    let resolved: Vec<String> = path.iter()
+                        .map(|x| match fs::canonicalize(x) {
+                            Ok(p) => p.to_string_lossy().into_owned(),
+                            Err(_) => x.to_string(),
+                        }).collect();
+                    let pair = profile.state.pair(&path);
+
+                    pairs.push(json!({
+                        "paths": path,
+                        "resolved": resolved,
+                        "changes": pair.map(|x| x.changes).unwrap_or(0),
+                        "last_result": pair.and_then(|x| x.last_result).map(|x| x.as_str()),
+                        "last_update": pair.and_then(|x| x.last_update),
+                        "degraded": pair.is_some_and(|x| x.degraded()),
+                        "failures": pair.map(|x| x.failures).unwrap_or(0),
+                        "last_error": pair.and_then(|x| x.last_error.clone()),
+                        "retry_after": pair.and_then(|x| x.retry_after),
+                        "last_verified": pair.and_then(|x| x.last_verified),
+                        "verified": pair.and_then(|x| x.verified),
+                    }));
+                }
+
+                entries.push(json!({
+                    "config": config.path,
+                    "user": profile.user.as_ref().map(|x| &x.name),
+                    "name": entry.name,
+                    "paths": entry.paths,
+                    "tags": entry.tags,
+                    "options": entry.options(),
+                    // Whether --only and --tags leave it in.
+                    "enabled": settings.selects(entry),
+                    "state": state,
+                    "paused_until": window.map(|x| x.until()),
+                    "stop_file": config.stop_file,
+                    "pairs": pairs,
+                }));
+            }
+        });
+    }
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&entries).unwrap()),
+        Output::Text => {
+            for entry in entries {
+                let paths: Vec<&str> = entry["paths"].as_array().unwrap().iter().map(|x| x.as_str().unwrap()).collect();
+                let label = entry["name"].as_str().map(String::from).unwrap_or_else(|| redact::join(&paths, " <-> "));
+                match entry["paused_until"].as_str() {
+                    Some(until) => println!("{} (paused until {})", label, until),
+                    None if entry["enabled"] == false => println!("{} (left out)", label),
+                    None => println!("{} ({})", label, entry["state"].as_str().unwrap_or("")),
+                }
+
+                for pair in entry["pairs"].as_array().unwrap() {
+                    let resolved: Vec<&str> = pair["resolved"].as_array().unwrap().iter().map(|x| x.as_str().unwrap()).collect();
+                    println!("\t{}", resolved.join(" <-> "));
+                    println!("\t\tlast result: {}", pair["last_result"].as_str().unwrap_or("never checked"));
+                    println!("\t\tchanges: {}", locale::number(pair["changes"].as_u64().unwrap_or(0)));
+                    if let Some(time) = pair["last_update"].as_u64() {
+                        println!("\t\tlast update: {}", locale::time(time, settings.utc));
+                    }
+                    if let Some(time) = pair["last_verified"].as_u64() {
+                        println!("\t\tlast verified: {}", locale::time(time, settings.utc));
+                    }
+                    if pair["verified"] == false {
+                        println!("\t\tverification: contents differ even though the mtimes match!");
+                    }
+                    if pair["degraded"] == true {
+                        println!("\t\tdegraded: {} failures in a row, last one: {}", pair["failures"], pair["last_error"].as_str().unwrap_or(""));
+                        if let Some(time) = pair["retry_after"].as_u64() {
+                            println!("\t\tretrying after: {}", locale::time(time, settings.utc));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells what the sandbox would do for these configs on this machine, since the daemon only says
+/// so with --verbose, at startup.
+fn doctor(settings: &Settings, profiles: &[Profile]) {
+    let wanted_by: Vec<String> = profiles.iter().filter(|x| x.config.sandbox).map(|x| x.config.path.display().to_string()).collect();
+    let landlock = sandbox::landlock_abi();
+    // Without landlock, the daemon runs without any of the sandbox.
+    let sandboxed = !wanted_by.is_empty() && landlock.is_ok();
+    let hosts: Vec<String> = profiles.iter().flat_map(|x| &x.config.entries)
+        .filter(|x| x.requires.iter().any(|x| matches!(x, requirements::Requirement::HostUp(_))))
+        .map(|x| x.label()).collect();
+    let network = !sandboxed || !sandbox::SYSCALL_FILTER || !hosts.is_empty();
+
+    match settings.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&json!({
+            "sandbox": wanted_by,
+            "landlock_abi": landlock.as_ref().ok(),
+            "landlock_error": landlock.as_ref().err().map(|x| x.to_string()),
+            "syscall_filter": sandbox::SYSCALL_FILTER,
+            "sandboxed": sandboxed,
+            "network": network,
+            "network_for": hosts,
+        })).unwrap()),
+        Output::Text => {
+            match landlock {
+                Ok(abi) => println!("Landlock: available (ABI {})", abi),
+                Err(ref e) => println!("Landlock: not available, {}", e),
+            }
+            println!("System call filter: {}", if sandbox::SYSCALL_FILTER { "available" } else { "not available on this architecture" });
+            if wanted_by.is_empty() {
+                println!("Sandbox: off, set \"sandbox\" to true in a config to turn it on");
+            } else if sandboxed {
+                println!("Sandbox: on, asked for by {}", wanted_by.join(", "));
+            } else {
+                println!("Sandbox: asked for by {}, but the daemon will run without it", wanted_by.join(", "));
+            }
+            if !network {
+                println!("Network: cut off, the daemon can't open any sockets but local ones");
+            } else if sandboxed && sandbox::SYSCALL_FILTER {
+                println!("Network: kept, for requires_host_up in {}", hosts.join(", "));
+            } else {
+                println!("Network: kept, nothing keeps the daemon from opening sockets");
+            }
+        }
+    }
+}
+
+/// Everything the daemon has to get to while syncing: what the entries could write, where the
+/// state is kept and, for git guards, the owners' git settings.
+fn sandbox_access(profiles: &[Profile]) -> sandbox::Access {
+    let mut access = sandbox::Access::default();
+    // Rewritten after every pass. Until it's there, that's allowed in its directory.
+    access.writable.extend(timing::output().map(|x| env::current_dir().map(|d| d.join(&x)).unwrap_or(x)));
+
+    for profile in profiles {
+        access.writable.extend(profile.config.entries.iter().flat_map(|x| x.roots()));
+        access.writable.extend(profile.state.path().parent().map(Path::to_path_buf));
+        access.network |= profile.config.entries.iter()
+            .any(|x| x.requires.iter().any(|x| matches!(x, requirements::Requirement::HostUp(_))));
+
+        if profile.config.entries.iter().any(|x| x.git_guard) {
+            let home = profile.user.as_ref().map(|x| x.home.clone()).or_else(dirs::home_dir);
+            if let Some(home) = home {
+                access.readable.push(home.join(".gitconfig"));
+                access.readable.push(home.join(".config/git"));
+            }
+        }
+    }
+
+    access
+}
+
+fn main() {
+    locale::init();
+    let (settings, mut profiles) = match setup() {
+        Ok(v) => v,
+        Err(e) => error(&e.to_string())
+    };
+
+    match settings.command {
+        Command::Diff => {
+            let mut any_differ = false;
+            for profile in &profiles {
+                // Pairs of a user that couldn't be switched to can't be checked.
+                any_differ |= look_as_owner(profile, |profile| diff(&settings, &profile.config)).unwrap_or(true);
+            }
+            exit(if any_differ { 1 } else { 0 });
+        }
+        Command::List => {
+            list(&settings, &profiles);
+            exit(0);
+        }
+        Command::Status(skipped) => {
+            if skipped { list_skipped(&settings, &profiles) } else { status(&settings, &profiles) }
+            exit(0);
+        }
+        Command::Simulate => {
+            let path = settings.snapshot.as_ref().unwrap();
+            let snapshot = match Snapshot::load(path) {
+                Ok(s) => s,
+                Err(e) => error(&format!("Couldn't load snapshot \"{}\": {}", path.display(), e)),
+            };
+
+            for profile in &profiles {
+                simulate(&profile.config, &snapshot);
+            }
+            exit(0);
+        }
+        Command::Snapshot => {
+            let mut snapshot = Snapshot { files: HashMap::new() };
+            for profile in &profiles {
+                let captured = look_as_owner(profile, |profile| {
+                    let pairs = pairs_of(&profile.config);
+                    let paths: Vec<&str> = pairs.iter().flat_map(|x| &x.1).map(String::as_str).collect();
+                    // Each file the way its own config hashes it.
+                    let hash = |path: &str| calculate_hash(profile.config.hash_algorithm, settings.buffer_size, path);
+                    Snapshot::capture(&paths, if settings.hashes { Some(hash) } else { None })
+                });
+                match captured {
+                    Some(Ok(s)) => snapshot.files.extend(s.files),
+                    Some(Err(e)) => error(&format!("Couldn't take snapshot: {}", e)),
+                    None => {}
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&snapshot.to_json()).unwrap());
+            exit(0);
+        }
+        Command::Explain(ref path) => {
+            let (profile, entry, paths) = match find_pair(&profiles, path) {
+                Some(f) => f,
+                None => error(&format!("No entry contains \"{}\"", path)),
+            };
+            let pair = profile.state.pair(&config::as_strs(&paths));
+
+            match look_as_owner(profile, |profile| explain::explain(&profile.config, entry, &paths, pair, settings.buffer_size)) {
+                Some(Ok(e)) => match settings.output {
+                    Output::Json => println!("{}", serde_json::to_string_pretty(&e.to_json()).unwrap()),
+                    Output::Text => e.print(),
+                },
+                Some(Err(e)) => error(&format!("Couldn't explain the entry: {}", e)),
+                None => exit(1),
+            }
+            exit(0);
+        }
+        Command::Compare(ref path, ref other) => {
+            let (profile, _, paths) = match find_pair(&profiles, path) {
+                Some(f) => f,
+                None => error(&format!("No entry contains \"{}\"", path)),
+            };
+
+            let compared = look_as_owner(profile, |profile| {
+                let history = profile.state.history().unwrap_or_else(|e| {
+                    warning(Warning::Failed, &format!("Couldn't read the history of \"{}\": {}", profile.config.path.display(), e));
+                    Vec::new()
+                });
+                compare(&settings, profile.config.hash_algorithm, &history, &paths, other)
+            });
+            match compared {
+                Some(Ok(matched)) => exit(if matched { 0 } else { 1 }),
+                Some(Err(e)) => error(&format!("Couldn't compare \"{}\": {}", other, e)),
+                None => exit(1),
+            }
+        }
+        Command::Export => {
+            export(&profiles);
+            exit(0);
+        }
+        Command::Duplicates => {
+            exit(if duplicates(&settings, &profiles) { 1 } else { 0 });
+        }
+        Command::Du => {
+            du(&settings, &profiles);
+            exit(0);
+        }
+        Command::Doctor => {
+            doctor(&settings, &profiles);
+            exit(0);
+        }
+        Command::Conflicts(None) => {
+            conflicts(&settings, &profiles);
+            exit(0);
+        }
+        Command::Conflicts(Some((ref id, keep))) => {
+            let profile = profiles.iter_mut()
+                .find(|x| look_as_owner(x, |x| x.state.conflicts().iter().any(|y| conflict_id(y.0) == *id)) == Some(true));
+            let resolved = match profile {
+                Some(profile) => run_as_owner(profile, |profile| resolve(&settings, profile, id, keep)),
+                None => error(&format!("No conflict \"{}\", see staticsync conflicts list", id)),
+            };
+            match resolved {
+                Some(Ok(())) => exit(0),
+                Some(Err(e)) => error(&format!("Couldn't resolve conflict \"{}\": {}", id, e)),
+                None => exit(1),
+            }
+        }
+        Command::StateRepair => {
+            for profile in profiles.iter_mut() {
+                run_as_owner(profile, |profile| state_repair(&settings, profile));
+            }
+            exit(0);
+        }
+        Command::History(ref path) => {
+            history(&settings, &profiles, path.as_deref());
+            exit(0);
+        }
+        Command::StateCompact => {
+            for profile in profiles.iter_mut() {
+                run_as_owner(profile, |profile| {
+                    let dropped = compact(profile);
+                    println!("\"{}\": dropped {} record(s)", profile.config.path.display(), dropped);
+                    if let Err(e) = profile.state.save() {
+                        error(&format!("Couldn't save sync state: {}", e));
+                    }
+                });
+            }
+            exit(0);
+        }
+        Command::Undo => {
+            for profile in profiles.iter_mut() {
+                run_as_owner(profile, undo);
+            }
+            exit(0);
+        }
+        Command::Init | Command::Artifacts => unreachable!("handled during setup"),
+        Command::Sync => {
+            if profiles.iter().any(|x| x.config.sandbox) {
+                let access = sandbox_access(&profiles);
+                match sandbox::restrict(&access) {
+                    Ok(()) if access.network => if settings.verbose { println!("Sandboxed, with network access for requires_host_up") },
+                    Ok(()) => if settings.verbose { println!("Sandboxed, without network access") },
+                    Err(e) => warning(Warning::Sandbox, &format!("Couldn't sandbox the daemon, running without it: {}", e)),
+                }
+            }
+        }
+    }
+
+    loop {
+        for profile in profiles.iter_mut() {
+            if profile.next_run > Instant::now() { continue }
+            if profile.stop_requested() {
+                profile.next_run = Instant::now() + profile.sleep_time;
+                continue;
+            }
+
+            // Whatever the triggers were about is covered by this pass.
+            profile.fired_triggers();
+            run_as_owner(profile, |profile| sync(&settings, profile, None));
+            profile.next_run = Instant::now() + profile.sleep_time;
+
+            if profile.next_verify.is_some_and(|x| x <= Instant::now()) {
+                run_as_owner(profile, |profile| verify(&settings, profile));
+                profile.next_verify = profile.config.verify_interval.map(|x| Instant::now() + x);
+            }
+
+            if profile.next_compact <= Instant::now() {
+                run_as_owner(profile, |profile| {
+                    let dropped = compact(profile);
+                    if settings.verbose { println!("Dropped {} stale record(s) from the sync state", dropped); }
+                    if let Err(e) = profile.state.save() {
+                        warning(Warning::State, &format!("Couldn't save sync state: {}", e));
+                    }
+                });
+                profile.next_compact = Instant::now() + COMPACT_INTERVAL;
+            }
+        }
+
+        if settings.once { break }
+
+        let next_run = profiles.iter().map(|x| x.next_run).min().unwrap();
+        let watching = profiles.iter().any(|x| x.config.entries.iter().any(|y| y.trigger_file.is_some()));
+        if !watching {
+            sleep(next_run.saturating_duration_since(Instant::now()));
+            continue;
+        }
+
+        // Keep an eye on trigger files while waiting for the next pass.
+        while Instant::now() < next_run {
+            sleep(next_run.saturating_duration_since(Instant::now()).min(TRIGGER_POLL));
+            for profile in profiles.iter_mut() {
+                let fired = profile.fired_triggers();
+                if fired.is_empty() || profile.stop_requested() { continue }
+                run_as_owner(profile, |profile| sync(&settings, profile, Some(&fired)));
+            }
+        }
     }
 }
@@ -0,0 +1,173 @@
+//! Three-way merges of text files, so changes made to both files of a pair can be kept when they
+//! don't touch the same lines, and merges left to a command that knows the format better.
+
+use std::fs;
+use std::io;
+use artifacts;
+use cow;
+use users;
+
+/// Biggest file merged, anything larger is unlikely to be notes or a dotfile.
+pub const MERGE_LIMIT: u64 = 1024 * 1024;
+/// Files further apart than this many line edits from their base aren't worth merging.
+const MAX_EDITS: isize = 2000;
+
+/// Whether `data` looks like text that can be merged line by line.
+pub fn is_text(data: &[u8]) -> bool {
+    data.len() as u64 <= MERGE_LIMIT && !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Asks `command` to merge `left` and `right`, which both changed since `base`, if that's known. It
+/// gets them as `$STATICSYNC_LEFT`, `$STATICSYNC_RIGHT` and `$STATICSYNC_BASE`, which is empty
+/// without a base, and writes what they merge into to `$STATICSYNC_OUTPUT`. Returns that, or
+/// nothing if the command gave up by failing or not writing anything.
+pub fn external(command: &str, left: &str, right: &str, base: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+    // Next to the files, named like staging files so they're never synced.
+    let base_file = format!("{}.base{}", left, artifacts::STAGING_SUFFIX);
+    let output = format!("{}.merged{}", left, artifacts::STAGING_SUFFIX);
+    let _ = fs::remove_file(&output);
+    if let Some(data) = base { fs::write(&base_file, data)?; }
+
+    let mut shell = cow::shell(command);
+    users::confine(&mut shell);
+    let status = shell
+        .env("STATICSYNC_LEFT", left)
+        .env("STATICSYNC_RIGHT", right)
+        .env("STATICSYNC_BASE", if base.is_some() { &base_file[..] } else { "" })
+        .env("STATICSYNC_OUTPUT", &output)
+        .status();
+    let _ = fs::remove_file(&base_file);
+
+    let merged = match status {
+        Ok(s) if s.success() => match fs::read(&output) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        },
+        Ok(_) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_file(&output);
+    merged
+}
+
+/// Combines the changes `ours` and `theirs` made to `base`. Returns nothing if they changed the
+/// same lines differently or are too far apart to tell.
+pub fn merge(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    // Where each line of the base ended up on both sides, if it's still there.
+    let mut in_ours = vec![None; base.len()];
+    let mut in_theirs = vec![None; base.len()];
+    for (i, j) in common(&base, &ours)? { in_ours[i] = Some(j); }
+    for (i, j) in common(&base, &theirs)? { in_theirs[i] = Some(j); }
+
+    let mut merged = String::new();
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    loop {
+        // The next line of the base both sides kept, everything up to it is a changed chunk.
+        let next = (i..base.len()).find(|&k| in_ours[k].is_some() && in_theirs[k].is_some());
+        let (k, ka, kb) = match next {
+            Some(k) => (k, in_ours[k].unwrap(), in_theirs[k].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        if (k, ka, kb) == (i, a, b) {
+            if next.is_none() { break }
+            merged.push_str(base[i]);
+            i += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+
+        let (original, x, y) = (&base[i..k], &ours[a..ka], &theirs[b..kb]);
+        let chunk = if x == original || x == y { y } else if y == original { x } else { return None };
+        chunk.iter().for_each(|line| merged.push_str(line));
+        i = k;
+        a = ka;
+        b = kb;
+    }
+
+    Some(merged)
+}
+
+/// The lines only `a` or only `b` has, starting with `-` and `+` like a diff without context.
+/// Returns nothing if they're too far apart to tell.
+pub fn diff(a: &str, b: &str) -> Option<String> {
+    let a: Vec<&str> = a.split_inclusive('\n').collect();
+    let b: Vec<&str> = b.split_inclusive('\n').collect();
+
+    let mut diff = String::new();
+    let mut add = |sign: char, line: &str| {
+        diff.push(sign);
+        diff.push_str(line);
+        if !line.ends_with('\n') { diff.push('\n'); }
+    };
+    let (mut i, mut j) = (0, 0);
+    for (k, l) in common(&a, &b)?.into_iter().chain(std::iter::once((a.len(), b.len()))) {
+        a[i..k].iter().for_each(|line| add('-', line));
+        b[j..l].iter().for_each(|line| add('+', line));
+        i = k + 1;
+        j = l + 1;
+    }
+
+    Some(diff)
+}
+
+/// Lines `a` and `b` have in common as pairs of indices, in order, going by Myers' diff.
+fn common(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let offset = n + m;
+    let mut v = vec![0isize; 2 * offset as usize + 2];
+    // How far each diagonal got after every number of edits, only the diagonals reachable then.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut edits = 0;
+
+    'search: for d in 0..=offset {
+        if d > MAX_EDITS { return None }
+        for k in (-d..=d).step_by(2) {
+            let (down, right) = (v[(k + 1 + offset) as usize], if k > -d { v[(k - 1 + offset) as usize] } else { 0 });
+            let mut x = if k == -d || (k != d && right < down) { down } else { right + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(k + offset) as usize] = x;
+            if x >= n && y >= m {
+                edits = d;
+                break 'search;
+            }
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+    }
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..=edits).rev() {
+        let before = &trace[d as usize - 1];
+        let at = |k: isize| before[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    while x > 0 && y > 0 {
+        x -= 1;
+        y -= 1;
+        pairs.push((x as usize, y as usize));
+    }
+
+    pairs.reverse();
+    Some(pairs)
+}
@@ -0,0 +1,49 @@
+//! Per-entry policies written as small Rhai scripts.
+
+use filetime::FileTime;
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+
+/// Keeps a runaway script from stalling the whole pass.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What a policy wants done with a pair about to be copied.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Copy,
+    Skip,
+    Conflict,
+}
+
+pub struct Policy {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Policy {
+    pub fn compile(source: &str) -> Result<Policy, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile_expression(source).map_err(|e| e.to_string())?;
+        Ok(Policy { engine, ast })
+    }
+
+    /// Runs the script with the pair's metadata in scope: `paths`, `size` and `mtime` (in unix
+    /// seconds) are arrays indexed like the entry's paths, and `newest`/`oldest` index into them.
+    pub fn evaluate(&self, paths: &[&str], size: &[u64], mtime: &[FileTime], newest: usize, oldest: usize)
+        -> Result<Verdict, String> {
+        let mut scope = Scope::new();
+        scope.push_constant("paths", paths.iter().map(|x| Dynamic::from(x.to_string())).collect::<Array>());
+        scope.push_constant("size", size.iter().map(|&x| Dynamic::from(x as i64)).collect::<Array>());
+        scope.push_constant("mtime", mtime.iter().map(|x| Dynamic::from(x.unix_seconds())).collect::<Array>());
+        scope.push_constant("newest", newest as i64);
+        scope.push_constant("oldest", oldest as i64);
+
+        let result: String = self.engine.eval_ast_with_scope(&mut scope, &self.ast).map_err(|e| e.to_string())?;
+        match result.as_str() {
+            "copy" => Ok(Verdict::Copy),
+            "skip" => Ok(Verdict::Skip),
+            "conflict" => Ok(Verdict::Conflict),
+            s => Err(format!("policy returned \"{}\", expected copy, skip or conflict", s)),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Stable pseudonyms for the paths staticsync logs while syncing, for logs that end up somewhere
+//! the names of the files shouldn't.
+
+use std::path::{Component, Path};
+use std::sync::OnceLock;
+use crypto::{digest::Digest, sha1::Sha1};
+
+static SALT: OnceLock<String> = OnceLock::new();
+
+/// Turns redaction on for the rest of the run. The same `salt` gives the same pseudonyms.
+pub fn enable(salt: &str) {
+    let _ = SALT.set(salt.to_string());
+}
+
+/// `path` the way it should show up in logs: as is, or with every part of it swapped for a
+/// pseudonym, so paths under the same directory still share a prefix.
+pub fn path<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    let salt = match SALT.get() {
+        Some(s) => s,
+        None => return path.display().to_string(),
+    };
+
+    let mut shown = String::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                let mut hasher = Sha1::new();
+                hasher.input_str(salt);
+                hasher.input_str(&name.to_string_lossy());
+                if !shown.ends_with('/') { shown.push('/'); }
+                shown.push_str(&hasher.result_str()[..8]);
+            }
+            Component::RootDir => shown.push('/'),
+            other => shown.push_str(&other.as_os_str().to_string_lossy()),
+        }
+    }
+    shown
+}
+
+/// Several paths, like the files of a pair, joined with `separator`.
+pub fn join<P: AsRef<Path>>(paths: &[P], separator: &str) -> String {
+    paths.iter().map(path).collect::<Vec<_>>().join(separator)
+}
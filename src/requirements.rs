@@ -0,0 +1,73 @@
+//! Things an entry needs from its surroundings before it can be synced, like a network share
+//! being mounted. Until they're there, the entry is put off rather than failing.
+
+use std::env;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use redact;
+
+/// Port tried on hosts given without one. Nothing listens on it these days, but a host that's up
+/// still answers by refusing the connection.
+const DEFAULT_PORT: u16 = 9;
+/// How long a host gets to answer before it counts as down.
+const HOST_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub enum Requirement {
+    /// Something has to be mounted there.
+    Mount(PathBuf),
+    /// The host, with an optional port, has to answer.
+    HostUp(String),
+    /// The program has to be somewhere in `$PATH`.
+    Command(String),
+}
+
+impl Requirement {
+    /// Why the requirement isn't met right now, if it isn't.
+    pub fn unmet(&self) -> Option<String> {
+        match *self {
+            Requirement::Mount(ref path) if !mounted(path) => Some(format!("\"{}\" isn't mounted", redact::path(path))),
+            Requirement::HostUp(ref host) if !up(host) => Some(format!("{} isn't up", host)),
+            Requirement::Command(ref name) if !installed(name) => Some(format!("{} isn't installed", name)),
+            _ => None,
+        }
+    }
+}
+
+/// Whether something is mounted at `path`, going by it being on another device than its parent.
+#[cfg(unix)]
+fn mounted(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (path.metadata(), path.join("..").metadata()) {
+        (Ok(meta), Ok(parent)) => meta.dev() != parent.dev() || meta.ino() == parent.ino(),
+        _ => false,
+    }
+}
+
+/// Without a portable way to tell mount points apart, they only have to be there.
+#[cfg(not(unix))]
+fn mounted(path: &Path) -> bool {
+    path.exists()
+}
+
+/// Whether `host` answers a connection, even if only to refuse it.
+fn up(host: &str) -> bool {
+    let addresses = match host.to_socket_addrs().or_else(|_| (host, DEFAULT_PORT).to_socket_addrs()) {
+        Ok(a) => a,
+        Err(_) => return false,
+    };
+
+    addresses.into_iter().any(|x| match TcpStream::connect_timeout(&x, HOST_TIMEOUT) {
+        Ok(_) => true,
+        Err(e) => e.kind() == ::std::io::ErrorKind::ConnectionRefused,
+    })
+}
+
+fn installed(name: &str) -> bool {
+    if Path::new(name).components().count() > 1 { return Path::new(name).is_file() }
+
+    let extensions: &[&str] = if cfg!(windows) { &["", ".exe", ".cmd", ".bat"] } else { &[""] };
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path)
+        .any(|dir| extensions.iter().any(|ext| dir.join(format!("{}{}", name, ext)).is_file())))
+}
@@ -0,0 +1,213 @@
+//! Confines the daemon to the files its configs are about, so a bug, or something that gets hold
+//! of it, can't reach the rest of the machine. Only does anything on Linux.
+
+use std::io;
+use std::path::PathBuf;
+
+/// Where the sandbox lets the daemon go, on top of the system paths it needs to run. Writable
+/// paths that don't exist yet count as their nearest existing parent, readable ones are left out.
+#[derive(Default)]
+pub struct Access {
+    pub writable: Vec<PathBuf>,
+    pub readable: Vec<PathBuf>,
+    /// Whether the network is needed after all, to see if hosts entries depend on are up.
+    pub network: bool,
+}
+
+/// What the daemon needs besides the files it syncs: shared libraries, git, time zones and
+/// randomness.
+const SYSTEM_PATHS: &[&str] = &["/usr", "/bin", "/lib", "/lib64", "/etc", "/dev/null", "/dev/urandom"];
+
+/// Restricts this process and anything it starts to `access` and drops the system calls the
+/// daemon has no business making, networking included since nothing it syncs is remote, unless
+/// `access` says otherwise. Can't be undone.
+#[cfg(target_os = "linux")]
+pub fn restrict(access: &Access) -> io::Result<()> {
+    linux::no_new_privs()?;
+    linux::landlock(access)?;
+    linux::seccomp(access.network)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_access: &Access) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "sandboxing is only supported on Linux"))
+}
+
+/// The landlock ABI this kernel has, for `staticsync doctor`, or why it has none.
+#[cfg(target_os = "linux")]
+pub fn landlock_abi() -> io::Result<libc::c_long> {
+    linux::landlock_abi()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn landlock_abi() -> io::Result<libc::c_long> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "landlock is only supported on Linux"))
+}
+
+/// Whether system calls can be filtered here at all.
+pub const SYSCALL_FILTER: bool = cfg!(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")));
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::mem::size_of;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::Path;
+    use std::ptr;
+    use libc::{self, c_long, c_ulong};
+    use super::{Access, SYSTEM_PATHS};
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const FS_EXECUTE: u64 = 1 << 0;
+    const FS_WRITE_FILE: u64 = 1 << 1;
+    const FS_READ_FILE: u64 = 1 << 2;
+    const FS_READ_DIR: u64 = 1 << 3;
+    /// Removing and making directories, files, links and the like, up to and including ABI v1.
+    const FS_CHANGE_DIR: u64 = 0b11_1111_1111 << 4;
+    const FS_REFER: u64 = 1 << 13;
+    const FS_TRUNCATE: u64 = 1 << 14;
+
+    const READ: u64 = FS_EXECUTE | FS_READ_FILE | FS_READ_DIR;
+    /// What makes sense for a rule on a file rather than a directory.
+    const FILE_ACCESS: u64 = FS_EXECUTE | FS_WRITE_FILE | FS_READ_FILE | FS_TRUNCATE;
+
+    const PR_SET_SECCOMP: libc::c_int = 22;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    /// Set in the numbers of x32 system calls, which come with the same `AUDIT_ARCH` as the
+    /// others on x86_64.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Nothing a file syncing daemon should ever do.
+    const DENIED: &[c_long] = &[
+        libc::SYS_ptrace, libc::SYS_process_vm_readv, libc::SYS_process_vm_writev,
+        libc::SYS_mount, libc::SYS_umount2, libc::SYS_pivot_root, libc::SYS_chroot,
+        libc::SYS_kexec_load, libc::SYS_init_module, libc::SYS_finit_module, libc::SYS_delete_module,
+        libc::SYS_bpf, libc::SYS_perf_event_open, libc::SYS_keyctl, libc::SYS_add_key,
+        libc::SYS_request_key, libc::SYS_reboot, libc::SYS_swapon, libc::SYS_swapoff,
+        libc::SYS_setns, libc::SYS_unshare, libc::SYS_io_uring_setup,
+    ];
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// The path itself if it's there, otherwise the closest of its parents that is.
+    fn nearest_existing(path: &Path) -> Option<&Path> {
+        path.ancestors().find(|x| x.exists())
+    }
+
+    fn check(ret: c_long) -> io::Result<c_long> {
+        if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+    }
+
+    /// Both landlock and seccomp filters need this to be allowed without root.
+    pub fn no_new_privs() -> io::Result<()> {
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as c_long).map(|_| ())
+    }
+
+    pub fn landlock_abi() -> io::Result<c_long> {
+        check(unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, ptr::null::<RulesetAttr>(), 0usize, CREATE_RULESET_VERSION)
+        })
+    }
+
+    pub fn landlock(access: &Access) -> io::Result<()> {
+        let abi = landlock_abi()?;
+
+        let mut handled = READ | FS_WRITE_FILE | FS_CHANGE_DIR;
+        if abi >= 2 { handled |= FS_REFER }
+        if abi >= 3 { handled |= FS_TRUNCATE }
+
+        let attr = RulesetAttr { handled_access_fs: handled };
+        let fd = check(unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, size_of::<RulesetAttr>(), 0u32)
+        })?;
+        let ruleset = unsafe { File::from_raw_fd(fd as i32) };
+
+        let readable = SYSTEM_PATHS.iter().map(Path::new).chain(access.readable.iter().map(|x| x.as_path()));
+        let rules = readable.map(|x| (x, READ)).chain(access.writable.iter().map(|x| (x.as_path(), handled)));
+        for (path, allowed) in rules {
+            let path = match nearest_existing(path) {
+                Some(p) if p == path || allowed != READ => p,
+                _ => continue,
+            };
+            let parent = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path)?;
+            let allowed = if path.is_dir() { allowed } else { allowed & FILE_ACCESS };
+
+            let rule = PathBeneathAttr { allowed_access: allowed & handled, parent_fd: parent.as_raw_fd() };
+            check(unsafe {
+                libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), RULE_PATH_BENEATH, &rule as *const PathBeneathAttr, 0u32)
+            })?;
+        }
+
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) }).map(|_| ())
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn seccomp(network: bool) -> io::Result<()> {
+        let statement = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+        let jump = |k: u32, jt: usize, jf: usize| libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16, jt: jt as u8, jf: jf as u8, k
+        };
+        let deny = statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+        // Anything made through another architecture's calling convention is refused outright,
+        // the syscall numbers below wouldn't mean the same there.
+        let mut filter = vec![
+            statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 4),
+            jump(AUDIT_ARCH, 1, 0),
+            deny,
+            statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0),
+        ];
+        // The same goes for x32, whose numbers would otherwise get past every check below.
+        #[cfg(target_arch = "x86_64")]
+        filter.extend_from_slice(&[
+            libc::sock_filter { code: (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16, jt: 0, jf: 1, k: X32_SYSCALL_BIT },
+            deny,
+        ]);
+        if !network {
+            filter.extend_from_slice(&[
+                // Sockets are only allowed for talking to other processes on the machine, like the
+                // name service when looking up users.
+                jump(libc::SYS_socket as u32, 0, 4),
+                statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 16),
+                jump(libc::AF_UNIX as u32, 0, 1),
+                statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+                deny,
+            ]);
+        }
+        for (i, &nr) in DENIED.iter().enumerate() {
+            // Past the rest of the checks and the allow, to the deny at the end.
+            filter.push(jump(nr as u32, DENIED.len() - i, 0));
+        }
+        filter.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        filter.push(deny);
+
+        let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+        let ret = unsafe {
+            libc::prctl(PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER as c_ulong, &program as *const libc::sock_fprog as c_ulong, 0, 0)
+        };
+        check(ret as c_long).map(|_| ())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn seccomp(_network: bool) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "syscall filtering isn't supported on this architecture"))
+    }
+}
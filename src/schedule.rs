@@ -0,0 +1,100 @@
+//! Times of the week when entries are left alone.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::Value as JSONValue;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A point in the week, in local time.
+#[derive(Clone, Copy)]
+pub struct WeekTime {
+    /// Days since Sunday.
+    pub day: usize,
+    /// Minutes since midnight.
+    pub minute: u32,
+}
+
+impl WeekTime {
+    #[cfg(unix)]
+    pub fn now() -> WeekTime {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0) as ::libc::time_t;
+        let mut tm: ::libc::tm = unsafe { ::std::mem::zeroed() };
+        unsafe { ::libc::localtime_r(&secs, &mut tm) };
+        WeekTime { day: tm.tm_wday as usize, minute: (tm.tm_hour * 60 + tm.tm_min) as u32 }
+    }
+
+    /// Without a portable way to get the local time zone, this is in UTC.
+    #[cfg(not(unix))]
+    pub fn now() -> WeekTime {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+        // The epoch was a Thursday.
+        WeekTime { day: ((secs / 86400 + 4) % 7) as usize, minute: ((secs % 86400) / 60) as u32 }
+    }
+}
+
+/// Entries with any of `tags` (or all of them, if there are none) are paused between `from` and
+/// `to` on the given days. A window that ends before it starts runs past midnight.
+pub struct PauseWindow {
+    days: [bool; 7],
+    from: u32,
+    to: u32,
+    pub tags: Vec<String>,
+}
+
+impl PauseWindow {
+    pub fn active_at(&self, now: WeekTime) -> bool {
+        let yesterday = (now.day + 6) % 7;
+
+        if self.from <= self.to {
+            self.days[now.day] && now.minute >= self.from && now.minute < self.to
+        } else {
+            (self.days[now.day] && now.minute >= self.from) || (self.days[yesterday] && now.minute < self.to)
+        }
+    }
+
+    pub fn applies_to(&self, tags: &[String]) -> bool {
+        self.tags.is_empty() || self.tags.iter().any(|x| tags.contains(x))
+    }
+
+    /// When the window is over, as HH:MM.
+    pub fn until(&self) -> String {
+        format!("{:02}:{:02}", self.to / 60, self.to % 60)
+    }
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) { return None }
+    Some(hours * 60 + minutes)
+}
+
+fn parse_strings(value: &JSONValue, key: &str) -> Result<Vec<String>, String> {
+    match value.get(key) {
+        Some(JSONValue::Array(items)) => items.iter()
+            .map(|x| x.as_str().map(String::from).ok_or_else(|| format!("\"{}\" must be a list of strings", key)))
+            .collect(),
+        Some(_) => Err(format!("\"{}\" must be a list of strings", key)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Parses a pause window like
+/// `{"days": ["mon", "fri"], "from": "09:00", "to": "18:00", "tags": ["noisy"]}`. Leaving out
+/// `days` means every day.
+pub fn parse_window(value: &JSONValue) -> Result<PauseWindow, String> {
+    let time = |key: &str| value.get(key).and_then(|x| x.as_str()).and_then(parse_time)
+        .ok_or_else(|| format!("Pause windows need \"{}\" as HH:MM", key));
+    let (from, to) = (time("from")?, time("to")?);
+
+    let names = parse_strings(value, "days")?;
+    let mut days = [names.is_empty(); 7];
+    for name in names {
+        match DAYS.iter().position(|x| name.to_lowercase().starts_with(x)) {
+            Some(day) => days[day] = true,
+            None => return Err(format!("Unknown day \"{}\"", name)),
+        }
+    }
+
+    Ok(PauseWindow { days, from, to, tags: parse_strings(value, "tags")? })
+}
@@ -0,0 +1,77 @@
+//! Encryption for what staticsync keeps about the files it syncs, since paths, hashes and update
+//! times can say a lot about files that are meant to be private.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+/// Start of every sealed file, so they can be told apart from plain ones.
+const MAGIC: &[u8] = b"staticsync-sealed\n";
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Derives a key from the contents of the file at `path`, whatever they are.
+    pub fn load(path: &Path) -> io::Result<Key> {
+        let contents = fs::read(path)?;
+        if contents.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the key file is empty"));
+        }
+
+        let mut key = [0u8; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(&contents);
+        hasher.result(&mut key);
+        Ok(Key(key))
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn seal(key: &Key, data: &[u8]) -> Vec<u8> {
+    let nonce = nonce();
+    let mut tag = [0u8; TAG_LEN];
+    let mut sealed = vec![0u8; data.len()];
+    ChaCha20Poly1305::new(&key.0, &nonce, MAGIC).encrypt(data, &mut sealed, &mut tag);
+
+    [MAGIC, &nonce[..], &tag[..], &sealed[..]].concat()
+}
+
+/// Decrypts what `seal` made, making sure it wasn't tampered with.
+pub fn open(key: &Key, data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let data = data.strip_prefix(MAGIC).ok_or_else(|| invalid("not a sealed file"))?;
+    if data.len() < NONCE_LEN + TAG_LEN { return Err(invalid("sealed file is truncated")) }
+
+    let (nonce, rest) = data.split_at(NONCE_LEN);
+    let (tag, sealed) = rest.split_at(TAG_LEN);
+    let mut opened = vec![0u8; sealed.len()];
+    if !ChaCha20Poly1305::new(&key.0, nonce, MAGIC).decrypt(sealed, &mut opened, tag) {
+        return Err(invalid("wrong key, or the file was tampered with"));
+    }
+
+    Ok(opened)
+}
+
+/// Only has to be unique for the key. The clock is a fallback for systems without /dev/urandom.
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    let random = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut nonce));
+
+    if random.is_err() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0);
+        nonce = now.to_le_bytes();
+    }
+
+    nonce
+}
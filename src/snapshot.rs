@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use filetime::FileTime;
+use serde_json::{self, Value as JSONValue};
+
+const SNAPSHOT_VERSION: u64 = 1;
+
+/// Metadata of a file at the time the snapshot was taken.
+pub struct FileRecord {
+    pub size: u64,
+    pub mtime: FileTime,
+    pub hash: Option<String>,
+}
+
+/// A point in time record of the configured files, good for replaying decisions later.
+pub struct Snapshot {
+    pub files: HashMap<String, FileRecord>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Snapshot {
+    /// Records the metadata of every path in `paths`, along with their hashes if `hash` is given.
+    pub fn capture<F>(paths: &[&str], hash: Option<F>) -> io::Result<Snapshot>
+        where F: Fn(&str) -> io::Result<String> {
+        let mut files = HashMap::new();
+
+        for path in paths {
+            let meta = fs::metadata(path)?;
+            let hash = match hash {
+                Some(ref f) => Some(f(path)?),
+                None => None,
+            };
+
+            files.insert(path.to_string(), FileRecord {
+                size: meta.len(),
+                mtime: FileTime::from_last_modification_time(&meta),
+                hash,
+            });
+        }
+
+        Ok(Snapshot { files })
+    }
+
+    pub fn to_json(&self) -> JSONValue {
+        let files: serde_json::Map<String, JSONValue> = self.files.iter().map(|(path, record)| {
+            (path.clone(), json!({
+                "size": record.size,
+                "mtime": record.mtime.unix_seconds(),
+                "mtime_nsec": record.mtime.nanoseconds(),
+                "hash": record.hash,
+            }))
+        }).collect();
+
+        json!({ "version": SNAPSHOT_VERSION, "files": files })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Snapshot> {
+        let value: JSONValue = serde_json::from_reader(File::open(path)?)?;
+
+        match value.get("version").and_then(|x| x.as_u64()) {
+            Some(SNAPSHOT_VERSION) => {}
+            Some(v) => return Err(invalid(format!("Unsupported snapshot version {}", v))),
+            None => return Err(invalid("Not a snapshot".to_string())),
+        }
+
+        let mut files = HashMap::new();
+        let list = value.get("files").and_then(|x| x.as_object())
+            .ok_or_else(|| invalid("Missing file list".to_string()))?;
+
+        for (path, record) in list {
+            let field = |key: &str| record.get(key).and_then(|x| x.as_u64())
+                .ok_or_else(|| invalid(format!("Missing \"{}\" for \"{}\"", key, path)));
+            let mtime = FileTime::from_unix_time(field("mtime")? as i64, field("mtime_nsec")? as u32);
+            let hash = record.get("hash").and_then(|x| x.as_str()).map(String::from);
+
+            files.insert(path.clone(), FileRecord { size: field("size")?, mtime, hash });
+        }
+
+        Ok(Snapshot { files })
+    }
+}
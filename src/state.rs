@@ -0,0 +1,657 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dirs;
+use filetime::FileTime;
+use backups;
+use decision::same_mtime;
+use history::{self, Event};
+use seal::{self, Key};
+use zstd;
+use serde_json::{self, Value as JSONValue};
+
+const STATE_VERSION: u64 = 1;
+/// Longest a failing pair is left alone between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// State bigger than this is compressed, like the records of directory entries with lots of files.
+const COMPRESS_THRESHOLD: usize = 64 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+/// What zstd frames start with. JSON never does, so plain state is told apart from compressed.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Outcome of the last time a pair was checked.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PassResult {
+    InSync,
+    Updated,
+    /// Something stopped the pair from being updated for now.
+    Held,
+    /// Checking or updating the pair went wrong.
+    Failed,
+}
+
+impl PassResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PassResult::InSync => "in-sync",
+            PassResult::Updated => "updated",
+            PassResult::Held => "held",
+            PassResult::Failed => "failed",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<PassResult> {
+        match s {
+            "in-sync" => Some(PassResult::InSync),
+            "updated" => Some(PassResult::Updated),
+            "held" => Some(PassResult::Held),
+            "failed" => Some(PassResult::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// What we remember about a pair between runs.
+#[derive(Default)]
+pub struct PairState {
+    /// Number of times the pair was found out of sync and updated.
+    pub changes: u64,
+    pub last_result: Option<PassResult>,
+    /// Unix time of the last update.
+    pub last_update: Option<u64>,
+    /// Number of passes in a row that failed.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    /// Unix time before which a failing pair isn't tried again.
+    pub retry_after: Option<u64>,
+    /// Unix time the contents were last re-hashed to make sure they really match.
+    pub last_verified: Option<u64>,
+    /// Whether they did.
+    pub verified: Option<bool>,
+    /// Inode of each file, to recognize them after they're renamed.
+    pub inodes: Vec<u64>,
+    /// Mtime all files were left with the last time the pair was in sync.
+    pub synced_mtime: Option<FileTime>,
+    /// Hash of their contents then, if it was checked.
+    pub synced_hash: Option<String>,
+    /// Unix time the files left were moved to the trash after the others were deleted.
+    pub trashed: Option<u64>,
+    /// Unix time the pair was first held because more than one of its files changed, for as long
+    /// as it still is.
+    pub conflict: Option<u64>,
+}
+
+impl PairState {
+    /// Whether the pair keeps failing and is only checked every now and then.
+    pub fn degraded(&self) -> bool {
+        self.failures > 0
+    }
+
+    /// Whether a file of the pair changed since it was last in sync, if that's known. Mtimes
+    /// within `tolerance` of the one it was left with count as the same.
+    pub fn changed(&self, mtime: FileTime, hash: Option<&str>, tolerance: Duration) -> Option<bool> {
+        let synced = self.synced_mtime?;
+        if same_mtime(mtime, synced, tolerance) { return Some(false) }
+
+        match (hash, self.synced_hash.as_deref()) {
+            (Some(a), Some(b)) => Some(a != b),
+            _ => Some(true),
+        }
+    }
+}
+
+/// Something a pass left alone, and why.
+#[derive(Clone, PartialEq)]
+pub struct Skip {
+    /// The pair, or the entry when the whole of it was skipped.
+    pub paths: Vec<String>,
+    pub reason: String,
+}
+
+/// A change the last pass that changed anything made to a file, and how to take it back.
+pub struct Undo {
+    /// The pair the file belongs to.
+    pub paths: Vec<String>,
+    pub file: String,
+    /// Mtime the file had before.
+    pub mtime: FileTime,
+    /// Where its contents from before are kept, unless only its mtime changed.
+    pub backup: Option<PathBuf>,
+}
+
+/// Persistent sync state, kept as JSON in the user's data directory.
+pub struct State {
+    path: PathBuf,
+    /// Hash of the config the state was last used with.
+    pub config_hash: Option<String>,
+    pairs: HashMap<Vec<String>, PairState>,
+    dirty: bool,
+    /// Encrypts the state on disk when set.
+    key: Option<Key>,
+    /// What the last pass over each entry skipped.
+    skipped: Vec<Skip>,
+    /// What the pass going on skipped so far.
+    skipping: Vec<Skip>,
+    /// Changes made by the last pass that changed anything.
+    journal: Vec<Undo>,
+    /// Whether the pass going on already started its own journal.
+    journaling: bool,
+    /// Sides the pass going on took a filesystem snapshot of, along with the error if it failed.
+    snapshots: HashMap<PathBuf, Option<String>>,
+    /// Unix times pairs were updated at lately, read from the history the first time they're needed
+    /// and kept up to date from then on.
+    updates: Option<HashMap<Vec<String>, Vec<u64>>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl State {
+    /// Where state is kept by default. Configs other than the default one get their own file,
+    /// named after `namespace`.
+    pub fn default_path(namespace: Option<&str>) -> Option<PathBuf> {
+        dirs::data_dir().map(|mut buf| {
+            buf.push("staticsync");
+            match namespace {
+                Some(ns) => buf.push(format!("state-{}.json", ns)),
+                None => buf.push("state.json"),
+            }
+            buf
+        })
+    }
+
+    /// A blank state that will be written to `path`.
+    pub fn empty(path: PathBuf, key: Option<Key>) -> State {
+        State {
+            path, config_hash: None, pairs: HashMap::new(), dirty: true, key, skipped: Vec::new(), skipping: Vec::new(),
+            journal: Vec::new(), journaling: false, snapshots: HashMap::new(), updates: None,
+        }
+    }
+
+    /// Brings state written by an older version up to date.
+    fn migrate(mut value: JSONValue) -> io::Result<JSONValue> {
+        let version = value.get("version").and_then(|x| x.as_u64()).unwrap_or(0);
+
+        if version > STATE_VERSION {
+            return Err(invalid("Written by a newer version of staticsync"));
+        }
+
+        // Version 0 is the same layout without the version field.
+        if version == 0 {
+            value["version"] = json!(1);
+        }
+
+        Ok(value)
+    }
+
+    /// Loads the state at `path`, starting from scratch if it doesn't exist yet. With a `key`, the
+    /// state is encrypted from the next save on.
+    pub fn load(path: PathBuf, key: Option<Key>) -> io::Result<State> {
+        let mut pairs = HashMap::new();
+        let mut config_hash = None;
+        let mut dirty = false;
+        let mut skipped = Vec::new();
+        let mut journal = Vec::new();
+
+        if path.is_file() {
+            let mut data = fs::read(&path)?;
+            match key {
+                Some(ref key) if seal::is_sealed(&data) => data = seal::open(key, &data)?,
+                // Written before there was a key, seal it right away.
+                Some(_) => dirty = true,
+                None if seal::is_sealed(&data) => return Err(invalid("It's encrypted, but no \"state_key\" is set")),
+                None => {}
+            }
+            if data.starts_with(&ZSTD_MAGIC) { data = zstd::decode_all(&data[..])?; }
+
+            let value: JSONValue = serde_json::from_slice(&data)?;
+            let value = State::migrate(value)?;
+            let entries = value.get("pairs").and_then(|x| x.as_array())
+                .ok_or_else(|| invalid("Missing pair list"))?;
+            config_hash = value.get("config_hash").and_then(|x| x.as_str()).map(String::from);
+
+            for entry in entries {
+                let paths: Vec<String> = entry.get("paths").and_then(|x| x.as_array())
+                    .ok_or_else(|| invalid("Pair without paths"))?
+                    .iter().filter_map(|x| x.as_str().map(String::from)).collect();
+                let changes = entry.get("changes").and_then(|x| x.as_u64()).unwrap_or(0);
+                let last_result = entry.get("last_result").and_then(|x| x.as_str()).and_then(PassResult::from_str);
+                let last_update = entry.get("last_update").and_then(|x| x.as_u64());
+                let failures = entry.get("failures").and_then(|x| x.as_u64()).unwrap_or(0) as u32;
+                let last_error = entry.get("last_error").and_then(|x| x.as_str()).map(String::from);
+                let retry_after = entry.get("retry_after").and_then(|x| x.as_u64());
+                let last_verified = entry.get("last_verified").and_then(|x| x.as_u64());
+                let verified = entry.get("verified").and_then(|x| x.as_bool());
+                let inodes = entry.get("inodes").and_then(|x| x.as_array())
+                    .map(|x| x.iter().filter_map(|x| x.as_u64()).collect()).unwrap_or_default();
+                let synced_mtime = entry.get("synced_mtime").and_then(|x| x.as_array()).and_then(|x| {
+                    Some(FileTime::from_unix_time(x.first()?.as_i64()?, x.get(1)?.as_u64()? as u32))
+                });
+                let synced_hash = entry.get("synced_hash").and_then(|x| x.as_str()).map(String::from);
+                let trashed = entry.get("trashed").and_then(|x| x.as_u64());
+                let conflict = entry.get("conflict").and_then(|x| x.as_u64());
+                pairs.insert(paths, PairState {
+                    changes, last_result, last_update, failures, last_error, retry_after, last_verified, verified, inodes,
+                    synced_mtime, synced_hash, trashed, conflict
+                });
+            }
+
+            for skip in value.get("skipped").and_then(|x| x.as_array()).into_iter().flatten() {
+                let paths = skip.get("paths").and_then(|x| x.as_array())
+                    .map(|x| x.iter().filter_map(|x| x.as_str().map(String::from)).collect());
+                let reason = skip.get("reason").and_then(|x| x.as_str()).map(String::from);
+                if let (Some(paths), Some(reason)) = (paths, reason) { skipped.push(Skip { paths, reason }); }
+            }
+
+            for undo in value.get("journal").and_then(|x| x.as_array()).into_iter().flatten() {
+                let paths = undo.get("paths").and_then(|x| x.as_array())
+                    .map(|x| x.iter().filter_map(|x| x.as_str().map(String::from)).collect());
+                let file = undo.get("file").and_then(|x| x.as_str()).map(String::from);
+                let mtime = undo.get("mtime").and_then(|x| x.as_array()).and_then(|x| {
+                    Some(FileTime::from_unix_time(x.first()?.as_i64()?, x.get(1)?.as_u64()? as u32))
+                });
+                let backup = undo.get("backup").and_then(|x| x.as_str()).map(PathBuf::from);
+                if let (Some(paths), Some(file), Some(mtime)) = (paths, file, mtime) {
+                    journal.push(Undo { paths, file, mtime, backup });
+                }
+            }
+        }
+
+        Ok(State { path, config_hash, pairs, dirty, key, skipped, skipping: Vec::new(), journal, journaling: false, snapshots: HashMap::new(), updates: None })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_config_hash(&mut self, hash: &str) {
+        if self.config_hash.as_deref() != Some(hash) {
+            self.config_hash = Some(hash.to_string());
+            self.dirty = true;
+        }
+    }
+
+    pub fn pair(&self, paths: &[&str]) -> Option<&PairState> {
+        let key: Vec<String> = paths.iter().map(|x| x.to_string()).collect();
+        self.pairs.get(&key)
+    }
+
+    pub fn pair_mut(&mut self, paths: &[&str]) -> &mut PairState {
+        self.dirty = true;
+        self.pairs.entry(paths.iter().map(|x| x.to_string()).collect()).or_default()
+    }
+
+    /// Every pair there's a record of.
+    pub fn known(&self) -> Vec<Vec<String>> {
+        self.pairs.keys().cloned().collect()
+    }
+
+    pub fn forget(&mut self, paths: &[&str]) {
+        let key: Vec<String> = paths.iter().map(|x| x.to_string()).collect();
+        if self.pairs.remove(&key).is_some() { self.dirty = true; }
+    }
+
+    /// Moves the record of a pair to the paths its files were renamed to.
+    pub fn rename(&mut self, from: &[&str], to: &[&str]) {
+        let key: Vec<String> = from.iter().map(|x| x.to_string()).collect();
+        if let Some(pair) = self.pairs.remove(&key) {
+            self.pairs.insert(to.iter().map(|x| x.to_string()).collect(), pair);
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_inodes(&mut self, paths: &[&str], inodes: Vec<u64>) {
+        if self.pair(paths).is_none_or(|x| x.inodes != inodes) {
+            self.pair_mut(paths).inodes = inodes;
+        }
+    }
+
+    /// Records that the files of the pair that were left just went to the trash, or with `false`,
+    /// that they came back out of it.
+    pub fn set_trashed(&mut self, paths: &[&str], trashed: bool) {
+        self.pair_mut(paths).trashed = if trashed { Some(unix_now()) } else { None };
+    }
+
+    /// How long the files of the pair have been in the trash, if they are.
+    pub fn in_trash(&self, paths: &[&str]) -> Option<Duration> {
+        let since = self.pair(paths)?.trashed?;
+        Some(Duration::from_secs(unix_now().saturating_sub(since)))
+    }
+
+    /// Notes that the pass going on left `paths` alone because of `reason`.
+    pub fn skip(&mut self, paths: &[&str], reason: &str) {
+        self.skipping.push(Skip { paths: paths.iter().map(|x| x.to_string()).collect(), reason: reason.to_string() });
+    }
+
+    /// Wraps up the journal of skipped files once a pass is done: what it noted replaces what
+    /// earlier passes noted about the pairs it went over, picked out by `covered`.
+    pub fn end_pass<F: Fn(&[String]) -> bool>(&mut self, covered: F) {
+        let mut skipped: Vec<Skip> = self.skipped.iter().filter(|x| !covered(&x.paths)).cloned().collect();
+        skipped.append(&mut self.skipping);
+        if skipped != self.skipped {
+            self.skipped = skipped;
+            self.dirty = true;
+        }
+        self.journaling = false;
+        self.snapshots.clear();
+    }
+
+    /// How taking a snapshot of `root` went in the pass going on, if it was tried.
+    pub fn snapshot(&self, root: &Path) -> Option<&Option<String>> {
+        self.snapshots.get(root)
+    }
+
+    pub fn record_snapshot(&mut self, root: &Path, error: Option<String>) {
+        self.snapshots.insert(root.to_path_buf(), error);
+    }
+
+    /// What the last passes skipped.
+    pub fn skipped(&self) -> &[Skip] {
+        &self.skipped
+    }
+
+    /// Makes way for the journal of the pass going on the first time it changes something, throwing
+    /// away the one before along with its backups.
+    pub fn start_journal(&mut self) {
+        if self.journaling { return }
+        for undo in self.journal.drain(..) {
+            if let Some(backup) = undo.backup { let _ = fs::remove_file(backup); }
+        }
+        self.journaling = true;
+        self.dirty = true;
+    }
+
+    /// Whether the pass going on already noted a change to `file`, the first one is what counts.
+    pub fn journaled(&self, file: &str) -> bool {
+        self.journaling && self.journal.iter().any(|x| x.file == file)
+    }
+
+    pub fn journal(&mut self, undo: Undo) {
+        self.journal.push(undo);
+        self.dirty = true;
+    }
+
+    /// Where the journal keeps the contents of the files it would put back, by pair.
+    pub fn undo_backups(&self) -> Vec<(&[String], &Path)> {
+        self.journal.iter().filter_map(|x| x.backup.as_deref().map(|y| (&x.paths[..], y))).collect()
+    }
+
+    /// Hands over the journal for undoing it, leaving it empty.
+    pub fn take_journal(&mut self) -> Vec<Undo> {
+        if !self.journal.is_empty() { self.dirty = true; }
+        self.journal.drain(..).collect()
+    }
+
+    /// Forgets what the files of a pair were like when they were last in sync, so they all count
+    /// as changed since and the pair is held like a conflict.
+    pub fn unsync(&mut self, paths: &[&str]) {
+        let pair = self.pair_mut(paths);
+        pair.synced_mtime = Some(FileTime::zero());
+        pair.synced_hash = None;
+    }
+
+    /// Looks for records that can't be right: pairs no longer in the config and updates that
+    /// happened in the future.
+    pub fn check<F: Fn(&[String]) -> bool>(&self, configured: F) -> Vec<String> {
+        let now = unix_now();
+        let mut problems = Vec::new();
+
+        for (paths, pair) in &self.pairs {
+            if !configured(paths) {
+                problems.push(format!("{} is no longer configured", paths.join(" <-> ")));
+            }
+
+            if pair.last_update.is_some_and(|x| x > now) {
+                problems.push(format!("{} was updated in the future", paths.join(" <-> ")));
+            }
+        }
+
+        problems.sort();
+        problems
+    }
+
+    /// Fixes whatever `check` finds. Returns how many records were touched.
+    pub fn repair<F: Fn(&[String]) -> bool>(&mut self, configured: F) -> usize {
+        let now = unix_now();
+        let before = self.pairs.len();
+        self.pairs.retain(|paths, _| configured(paths));
+        let mut fixed = before - self.pairs.len();
+
+        for pair in self.pairs.values_mut() {
+            if pair.last_update.is_some_and(|x| x > now) {
+                pair.last_update = None;
+                fixed += 1;
+            }
+        }
+
+        if fixed > 0 { self.dirty = true; }
+        fixed
+    }
+
+    /// Whether the pair failed recently enough that it should be left alone for now.
+    pub fn backing_off(&self, paths: &[&str]) -> bool {
+        self.pair(paths).and_then(|x| x.retry_after).is_some_and(|x| x > unix_now())
+    }
+
+    /// Records that the pair couldn't be processed and pushes its next attempt back, twice as far
+    /// every time it fails in a row, starting at `interval`. Returns whether the error differs
+    /// from last time, so the same one isn't reported over and over.
+    pub fn record_failure(&mut self, paths: &[&str], error: &str, interval: Duration) -> bool {
+        let pair = self.pair_mut(paths);
+        let new = pair.last_error.as_deref() != Some(error);
+        let backoff = interval.checked_mul(1 << pair.failures.min(16)).unwrap_or(MAX_BACKOFF).min(MAX_BACKOFF);
+
+        pair.failures += 1;
+        pair.last_result = Some(PassResult::Failed);
+        pair.last_error = Some(error.to_string());
+        pair.retry_after = Some(unix_now() + backoff.as_secs());
+        new
+    }
+
+    /// Records what the files of a pair were like once all of them matched. A `hash` of `None`
+    /// keeps the last one as long as the mtime is the same.
+    pub fn record_sync(&mut self, paths: &[&str], mtime: FileTime, hash: Option<String>) {
+        let (same_mtime, same_hash) = match self.pair(paths) {
+            Some(x) => (x.synced_mtime == Some(mtime), hash.is_none() || x.synced_hash == hash),
+            None => (false, false),
+        };
+        if same_mtime && same_hash { return }
+
+        let pair = self.pair_mut(paths);
+        if !same_mtime || hash.is_some() { pair.synced_hash = hash; }
+        pair.synced_mtime = Some(mtime);
+    }
+
+    /// Records whether re-hashing a pair that was in sync showed the same contents.
+    pub fn record_verification(&mut self, paths: &[&str], ok: bool) {
+        let pair = self.pair_mut(paths);
+        pair.last_verified = Some(unix_now());
+        pair.verified = Some(ok);
+    }
+
+    /// Queues the pair as a conflict for someone to sort out, unless it already is.
+    pub fn record_conflict(&mut self, paths: &[&str]) {
+        if self.pair(paths).is_some_and(|x| x.conflict.is_some()) { return }
+        self.pair_mut(paths).conflict = Some(unix_now());
+    }
+
+    /// Pairs queued as conflicts, along with when they were found.
+    pub fn conflicts(&self) -> Vec<(&[String], u64)> {
+        self.pairs.iter().filter_map(|(paths, pair)| pair.conflict.map(|x| (&paths[..], x))).collect()
+    }
+
+    /// Records the outcome of checking a pair in this pass. One that isn't held anymore is no
+    /// longer a conflict, however that was sorted out.
+    pub fn record(&mut self, paths: &[&str], result: PassResult) {
+        if result != PassResult::Held && self.pair(paths).is_some_and(|x| x.conflict.is_some()) {
+            self.pair_mut(paths).conflict = None;
+        }
+
+        // Avoid rewriting the state file every pass when nothing happens.
+        let degraded = self.pair(paths).is_some_and(|x| x.degraded());
+        if result != PassResult::Updated && !degraded && self.pair(paths).and_then(|x| x.last_result) == Some(result) {
+            return;
+        }
+
+        let pair = self.pair_mut(paths);
+        pair.last_result = Some(result);
+        pair.failures = 0;
+        pair.last_error = None;
+        pair.retry_after = None;
+
+        if result == PassResult::Updated {
+            pair.changes += 1;
+            pair.last_update = Some(unix_now());
+            pair.verified = None;
+
+            // Not read yet, the history already has it.
+            if let Some(ref mut updates) = self.updates {
+                updates.entry(paths.iter().map(|x| x.to_string()).collect()).or_default().push(unix_now());
+            }
+        }
+    }
+
+    /// How many times the pair was updated in the last hour, going by the history, so updates made
+    /// by earlier runs count too.
+    pub fn updates_in_last_hour(&mut self, paths: &[&str]) -> usize {
+        let now = unix_now();
+        if self.updates.is_none() {
+            let mut updates: HashMap<Vec<String>, Vec<u64>> = HashMap::new();
+            // Every file a pass updates is an event of its own, from the same file to the same
+            // contents, they're one update of the pair.
+            let mut seen = HashSet::new();
+            for event in self.history().unwrap_or_default().into_iter().filter(|x| x.time + 3600 > now) {
+                if seen.insert((event.paths.clone(), event.time, event.from, event.hash)) {
+                    updates.entry(event.paths).or_default().push(event.time);
+                }
+            }
+            self.updates = Some(updates);
+        }
+
+        let key: Vec<String> = paths.iter().map(|x| x.to_string()).collect();
+        let updates = self.updates.as_mut().unwrap().entry(key).or_default();
+        updates.retain(|&x| x + 3600 > now);
+        updates.len()
+    }
+
+    /// Writes the state back to disk if anything changed since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty { return Ok(()) }
+
+        let pairs: Vec<JSONValue> = self.pairs.iter().map(|(paths, pair)| json!({
+            "paths": paths,
+            "changes": pair.changes,
+            "last_result": pair.last_result.map(|x| x.as_str()),
+            "last_update": pair.last_update,
+            "failures": pair.failures,
+            "last_error": pair.last_error,
+            "retry_after": pair.retry_after,
+            "last_verified": pair.last_verified,
+            "verified": pair.verified,
+            "inodes": pair.inodes,
+            "synced_mtime": pair.synced_mtime.map(|x| json!([x.unix_seconds(), x.nanoseconds()])),
+            "synced_hash": pair.synced_hash,
+            "trashed": pair.trashed,
+            "conflict": pair.conflict,
+        })).collect();
+        let skipped: Vec<JSONValue> = self.skipped.iter().map(|x| json!({ "paths": x.paths, "reason": x.reason })).collect();
+        let journal: Vec<JSONValue> = self.journal.iter().map(|x| json!({
+            "paths": x.paths,
+            "file": x.file,
+            "mtime": [x.mtime.unix_seconds(), x.mtime.nanoseconds()],
+            "backup": x.backup,
+        })).collect();
+        let value = json!({
+            "version": STATE_VERSION, "config_hash": self.config_hash, "pairs": pairs, "skipped": skipped, "journal": journal
+        });
+
+        if let Some(dir) = self.path.parent() { fs::create_dir_all(dir)?; }
+        let tmp = self.path.with_extension("json.tmp");
+        let mut data = serde_json::to_vec(&value)?;
+        // Compressed before it's encrypted, there's nothing left to compress after.
+        if data.len() > COMPRESS_THRESHOLD { data = zstd::encode_all(&data[..], COMPRESSION_LEVEL)?; }
+        match self.key {
+            Some(ref key) => fs::write(&tmp, seal::seal(key, &data))?,
+            None => fs::write(&tmp, data)?,
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.prune_bases();
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.path.with_extension("history")
+    }
+
+    /// Adds `event` to the history of updated files, encrypted like the state.
+    pub fn log(&self, event: &Event) -> io::Result<()> {
+        history::append(&self.history_path(), self.key.as_ref(), event)
+    }
+
+    pub fn history(&self) -> io::Result<Vec<Event>> {
+        history::read(&self.history_path(), self.key.as_ref())
+    }
+
+    /// Replaces the history with `events`.
+    pub fn set_history(&self, events: &[Event]) -> io::Result<()> {
+        history::rewrite(&self.history_path(), self.key.as_ref(), events)
+    }
+
+    /// Where the contents of files as they were when last in sync are kept, for merging.
+    pub fn bases_dir(&self) -> PathBuf {
+        self.path.with_extension("bases")
+    }
+
+    /// Keeps `data`, the contents of a pair as they were when it was last in sync, under `hash`.
+    pub fn store_base(&self, hash: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.bases_dir().join(hash);
+        if path.exists() { return Ok(()) }
+
+        let fresh = !self.bases_dir().exists();
+        fs::create_dir_all(self.bases_dir())?;
+        if fresh { backups::exclude(&self.bases_dir()); }
+        let tmp = path.with_extension("tmp");
+        match self.key {
+            Some(ref key) => fs::write(&tmp, seal::seal(key, data))?,
+            None => fs::write(&tmp, data)?,
+        }
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn has_base(&self, hash: &str) -> bool {
+        self.bases_dir().join(hash).is_file()
+    }
+
+    /// What a pair whose files last had `hash` when they were in sync looked like then.
+    pub fn base(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.bases_dir().join(hash))?;
+        match self.key {
+            Some(ref key) => seal::open(key, &data),
+            None => Ok(data),
+        }
+    }
+
+    /// Drops the bases no pair is in sync with anymore.
+    fn prune_bases(&self) {
+        let items = match fs::read_dir(self.bases_dir()) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+
+        for item in items.flatten() {
+            let name = item.file_name().to_string_lossy().into_owned();
+            if !self.pairs.values().any(|x| x.synced_hash.as_deref() == Some(&name)) {
+                let _ = fs::remove_file(item.path());
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Where the time of passes goes, for `--profile`. Totals are kept per entry and per kind of work,
+//! and written out as folded stacks, the format `inferno-flamegraph` and `flamegraph.pl` read.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+use config::Entry;
+
+/// Microseconds spent on each kind of work, by entry, once profiling is on.
+static TOTALS: Mutex<BTreeMap<(String, &'static str), u64>> = Mutex::new(BTreeMap::new());
+static OUTPUT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Turns profiling on for the rest of the run, writing to `path`.
+pub fn enable(path: PathBuf) {
+    *OUTPUT.lock().unwrap() = Some(path);
+}
+
+/// Where the profile goes, if profiling is on.
+pub fn output() -> Option<PathBuf> {
+    OUTPUT.lock().unwrap().clone()
+}
+
+fn enabled() -> bool {
+    OUTPUT.lock().unwrap().is_some()
+}
+
+/// Runs `f`, counting the time it takes as `phase` of `entry`.
+pub fn measure<T, F: FnOnce() -> T>(entry: &Entry, phase: &'static str, f: F) -> T {
+    if !enabled() { return f() }
+
+    let start = Instant::now();
+    let result = f();
+    let spent = start.elapsed().as_micros() as u64;
+    // Semicolons separate frames, and the count comes after the last space.
+    let frame = entry.label().replace(';', ",").replace('\n', " ");
+    *TOTALS.lock().unwrap().entry((frame, phase)).or_insert(0) += spent;
+    result
+}
+
+/// Writes everything measured so far, if profiling is on. Every pass rewrites the whole file, so
+/// it always covers the run up to then.
+pub fn save() -> io::Result<()> {
+    let path = match *OUTPUT.lock().unwrap() {
+        Some(ref p) => p.clone(),
+        None => return Ok(()),
+    };
+
+    let lines: String = TOTALS.lock().unwrap().iter()
+        .map(|((entry, phase), micros)| format!("staticsync;{};{} {}\n", entry, phase, micros))
+        .collect();
+    fs::write(path, lines)
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Where system mode looks for per-user configs, named after the user they belong to.
+pub const USERS_DIR: &str = "/etc/staticsync/users.d";
+
+/// An account whose syncs are done with its own privileges.
+pub struct User {
+    pub name: String,
+    pub home: PathBuf,
+    #[cfg(unix)]
+    uid: ::libc::uid_t,
+    #[cfg(unix)]
+    gid: ::libc::gid_t,
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::ptr;
+    use libc;
+
+    pub fn running_as_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    pub fn lookup(name: &str) -> Option<User> {
+        let cname = CString::new(name).ok()?;
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut buf: Vec<libc::c_char> = vec![0; 16384];
+        let mut result: *mut libc::passwd = ptr::null_mut();
+
+        let ret = unsafe {
+            libc::getpwnam_r(cname.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        };
+        if ret != 0 || result.is_null() { return None }
+
+        let home = unsafe { CStr::from_ptr(pwd.pw_dir) }.to_string_lossy().into_owned();
+        Some(User { name: name.to_string(), home: PathBuf::from(home), uid: pwd.pw_uid, gid: pwd.pw_gid })
+    }
+
+    /// Switches back to root once dropped.
+    pub struct Impersonation {
+        groups: Vec<libc::gid_t>,
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+
+    /// Takes on the effective identity of `user`, including their supplementary groups.
+    pub fn impersonate(user: &User) -> io::Result<Impersonation> {
+        let name = CString::new(user.name.as_str()).unwrap();
+        let mut groups: Vec<libc::gid_t> = vec![0; 256];
+
+        unsafe {
+            let n = libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr());
+            if n < 0 { return Err(io::Error::last_os_error()) }
+            groups.truncate(n as usize);
+
+            // Group changes need root, so they have to happen before giving up the uid.
+            check(libc::initgroups(name.as_ptr(), user.gid as _))?;
+            check(libc::setegid(user.gid))?;
+            check(libc::seteuid(user.uid))?;
+        }
+
+        Ok(Impersonation { groups })
+    }
+
+    /// Makes `command` run as the user being impersonated for good. Only the effective ids are
+    /// theirs, so a child left as is could just switch back to root, and so could anything it runs.
+    pub fn confine(command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if unsafe { libc::getuid() } != 0 || uid == 0 { return }
+
+        // Root again for a moment, in the child only, so that setting the ids sets all three.
+        // Supplementary groups are already the user's, from `impersonate`.
+        unsafe {
+            command.pre_exec(move || {
+                check(libc::seteuid(0))?;
+                check(libc::setgid(gid))?;
+                check(libc::setuid(uid))?;
+                if libc::setuid(0) == 0 {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "couldn't give up root"));
+                }
+                Ok(())
+            });
+        }
+    }
+
+    impl Drop for Impersonation {
+        fn drop(&mut self) {
+            // Carrying on as somebody else would be far worse than stopping.
+            unsafe {
+                if libc::seteuid(0) != 0 || libc::setegid(0) != 0
+                    || libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) != 0 {
+                    panic!("Couldn't switch back to root: {}", io::Error::last_os_error());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn running_as_root() -> bool {
+        false
+    }
+
+    pub fn lookup(_name: &str) -> Option<User> {
+        None
+    }
+
+    pub struct Impersonation;
+
+    pub fn confine(_command: &mut Command) {}
+
+    pub fn impersonate(_user: &User) -> io::Result<Impersonation> {
+        Err(io::Error::new(io::ErrorKind::Other, "System mode is only supported on Unix"))
+    }
+}
+
+pub use self::imp::*;
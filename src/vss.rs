@@ -0,0 +1,87 @@
+//! Reads files other programs keep locked on Windows, like Outlook's PST files or browser profiles,
+//! through a Volume Shadow Copy: a frozen view of the whole volume, taken at one moment, that
+//! nothing holds locks in. Shadow copies are made with WMI, which needs administrator rights.
+
+use std::collections::HashMap;
+use std::io;
+use std::process::Command;
+
+/// A shadow copy of a volume.
+struct Shadow {
+    id: String,
+    /// What paths inside it start with, like `\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3`.
+    device: String,
+}
+
+/// The shadow copies made during a pass, by volume. They're deleted once it's dropped, since each
+/// one keeps a copy of every block written to its volume from then on.
+#[derive(Default)]
+pub struct Shadows {
+    made: HashMap<String, Shadow>,
+}
+
+impl Shadows {
+    /// Where to read `path` from: `path` itself, or the same file in a shadow copy of its volume if
+    /// another program keeps it locked.
+    pub fn readable(&mut self, path: &str) -> io::Result<String> {
+        if !locked(path) { return Ok(path.to_string()) }
+
+        let (volume, rest) = split_volume(path)
+            .ok_or_else(|| io::Error::other(format!("\"{}\" isn't on a volume that can be shadow copied", path)))?;
+        if !self.made.contains_key(volume) {
+            self.made.insert(volume.to_string(), create(volume)?);
+        }
+        Ok(format!("{}\\{}", self.made[volume].device, rest))
+    }
+}
+
+impl Drop for Shadows {
+    fn drop(&mut self) {
+        for shadow in self.made.values() {
+            let _ = powershell(&format!(
+                "Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | Remove-CimInstance", shadow.id));
+        }
+    }
+}
+
+/// Splits `C:\Users\me\file` into `C:\` and `Users\me\file`.
+fn split_volume(path: &str) -> Option<(&str, &str)> {
+    let bytes = path.as_bytes();
+    let drive = bytes.len() > 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && (bytes[2] == b'\\' || bytes[2] == b'/');
+    if drive { Some((&path[..3], path[3..].trim_start_matches(['\\', '/']))) } else { None }
+}
+
+fn create(volume: &str) -> io::Result<Shadow> {
+    let output = powershell(&format!(
+        "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+         if ($r.ReturnValue -ne 0) {{ exit $r.ReturnValue }}; \
+         $s = Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; $s.ID; $s.DeviceObject", volume))?;
+    let mut lines = output.lines().map(str::trim).filter(|x| !x.is_empty());
+    match (lines.next(), lines.next()) {
+        (Some(id), Some(device)) => Ok(Shadow { id: id.to_string(), device: device.to_string() }),
+        _ => Err(io::Error::other(format!("couldn't find the shadow copy of {} that was just made", volume))),
+    }
+}
+
+fn powershell(script: &str) -> io::Result<String> {
+    let output = Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", script]).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        // 1 is what Win32_ShadowCopy.Create gives without administrator rights.
+        Err(io::Error::other(format!("couldn't make a shadow copy ({}), is staticsync running as administrator?", output.status)))
+    }
+}
+
+/// Whether another program opened `path` without letting anyone else read it.
+#[cfg(windows)]
+fn locked(path: &str) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    matches!(::std::fs::File::open(path), Err(ref e) if matches!(e.raw_os_error(), Some(32) | Some(33)))
+}
+
+/// Locks elsewhere never keep anyone from reading.
+#[cfg(not(windows))]
+fn locked(_path: &str) -> bool {
+    false
+}
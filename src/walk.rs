@@ -0,0 +1,64 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use artifacts;
+use exclude::{Exclude, GitIgnore};
+
+/// Lists every file under `root`, relative to it. Symlinked directories aren't followed, so a link
+/// back up the tree can't send this in circles, and staticsync's own temp files and trash are left
+/// out along with anything `exclude` matches or `gitignore` ignores.
+pub fn files(root: &Path, exclude: &Exclude, gitignore: Option<&GitIgnore>) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(dir) = pending.pop() {
+        for item in fs::read_dir(root.join(&dir))? {
+            let item = item?;
+            let relative = dir.join(item.file_name());
+            let kind = item.file_type()?;
+
+            let ignored = gitignore.is_some_and(|x| x.ignores(&relative, kind.is_dir()));
+            if ignored || exclude.excludes(&relative, kind.is_dir()) || artifacts::is_artifact(&relative) {
+                continue;
+            } else if kind.is_dir() {
+                pending.push(relative);
+            } else if kind.is_file() || (kind.is_symlink() && root.join(&relative).is_file()) {
+                found.push(relative);
+            }
+        }
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+/// How many bytes the files under `root` take up, everything included this time. Anything that
+/// can't be read counts as empty, like `root` not being there at all.
+pub fn size(root: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for item in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            match item.file_type() {
+                Ok(kind) if kind.is_dir() => pending.push(item.path()),
+                Ok(kind) if kind.is_file() => total += item.metadata().map(|x| x.len()).unwrap_or(0),
+                _ => (),
+            }
+        }
+    }
+
+    total
+}
+
+/// Number that stays with a file when it's renamed within the same filesystem.
+#[cfg(unix)]
+pub fn inode(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+pub fn inode(_meta: &Metadata) -> Option<u64> {
+    None
+}
@@ -0,0 +1,86 @@
+//! Codes for the warnings staticsync gives, so the ones that are known and expected can be turned
+//! off without hiding new problems.
+
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Warning {
+    /// Both files of a pair changed since the last sync.
+    Conflict,
+    /// A write was refused by `protected_paths` or `read_only_roots`.
+    Refused,
+    /// A file wasn't replaced because of uncommitted changes.
+    Uncommitted,
+    /// The policy script held a copy or failed.
+    Policy,
+    /// A replacement failed `verify_replace`.
+    Suspicious,
+    /// Some of the paths of an entry aren't there.
+    Unavailable,
+    /// An entry turned out not to make sense once its paths showed up, or after they changed type.
+    Invalid,
+    /// Something couldn't be synced, listed, moved or deleted.
+    Failed,
+    /// Deletions were held because one of the roots is empty.
+    EmptyRoot,
+    /// A group of entries was held together.
+    Group,
+    /// Files with the same mtime have different contents.
+    Mismatch,
+    /// The config changed since it was last used.
+    ConfigChanged,
+    /// The sync state couldn't be loaded, saved or has problems.
+    State,
+    /// Switching to the user of a system config failed.
+    User,
+    /// The daemon couldn't be sandboxed.
+    Sandbox,
+    /// Files of a pair differ while only observing.
+    Diverged,
+    /// A file drifted from its golden copy and was put back.
+    Drift,
+    /// A pair was updated more often than `max_changes_per_hour`.
+    Churn,
+}
+
+const CODES: &[(Warning, &str)] = &[
+    (Warning::Conflict, "W001"),
+    (Warning::Refused, "W002"),
+    (Warning::Uncommitted, "W003"),
+    (Warning::Policy, "W004"),
+    (Warning::Suspicious, "W005"),
+    (Warning::Unavailable, "W006"),
+    (Warning::Invalid, "W007"),
+    (Warning::Failed, "W008"),
+    (Warning::EmptyRoot, "W009"),
+    (Warning::Group, "W010"),
+    (Warning::Mismatch, "W011"),
+    (Warning::ConfigChanged, "W012"),
+    (Warning::State, "W013"),
+    (Warning::User, "W014"),
+    (Warning::Sandbox, "W015"),
+    (Warning::Diverged, "W016"),
+    (Warning::Drift, "W017"),
+    (Warning::Churn, "W018"),
+];
+
+static SUPPRESSED: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+impl Warning {
+    pub fn code(self) -> &'static str {
+        CODES.iter().find(|x| x.0 == self).map(|x| x.1).unwrap()
+    }
+
+    pub fn from_code(code: &str) -> Option<Warning> {
+        CODES.iter().find(|x| x.1.eq_ignore_ascii_case(code)).map(|x| x.0)
+    }
+}
+
+/// Turns `warnings` off for the rest of the run, everywhere.
+pub fn suppress(warnings: &[Warning]) {
+    SUPPRESSED.lock().unwrap().extend_from_slice(warnings);
+}
+
+pub fn suppressed(warning: Warning) -> bool {
+    SUPPRESSED.lock().unwrap().contains(&warning)
+}